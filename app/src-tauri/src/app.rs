@@ -1,15 +1,28 @@
 use ashuk_core::{
-    compresser::{
-        compress_to_target_extension, CompressError, CompressOptions, Result as CompressResult,
-        Status,
-    },
-    format_meta::{CompressOptionsContext, ImageFormat, ProcessStrategy},
+    batch::Batch,
+    classify::{self, Classification},
+    compresser::{self, CompressError, CompressOptions, Result as CompressResult, Status},
+    config::{Config, Preset},
+    curve::{CurveOptions, QualityCurve},
+    diff::{self, DiffOptions, DiffReport},
+    format_meta::{CompressOptionsContext, FormatDetection, ImageFormat},
+    jpeg_quality,
+    jpegtran::JpegTransformOptions,
+    manifest::{Manifest, ManifestEntry, PictureOptions},
+    metrics::ErrorStats,
+    preview::{Region, Source},
+    report::{InputInfo, ReportEntry, ReportFormat, ReportWriter},
+    scheduler::{Job, ScheduleEvent, Scheduler},
+    size_budget::{self, SizeBudget},
+    thumbnail::ContactSheet,
+    watch::{WatchOptions, Watcher},
 };
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use tauri::Manager;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::sync::Mutex;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -17,6 +30,12 @@ pub struct InputResult {
     pub path: String,
     pub size: u64,
     pub extension: String,
+    /// photo, graphic or text, and whether transparency is really used
+    #[serde(default)]
+    pub content: Option<Classification>,
+    /// estimated quality a jpg was saved at
+    #[serde(default)]
+    pub quality: Option<f32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,13 +43,65 @@ pub struct FileContext {
     pub status: Status,
     pub input: InputResult,
     pub output: Option<CompressResult>,
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 type FileList = HashMap<String, FileContext>;
 
+const FILE_EMITTER_NAME: &str = "listen-file";
+
 pub struct FileState {
     files: Mutex<FileList>,
     options: Mutex<CompressOptions>,
+    config: Mutex<Config>,
+}
+
+#[derive(Default)]
+pub struct WatchState {
+    watcher: Mutex<Option<Watcher>>,
+}
+
+/// Last decoded source, so moving the quality slider only re-encodes.
+#[derive(Default)]
+pub struct PreviewState {
+    source: Mutex<Option<(Option<std::time::SystemTime>, Source)>>,
+}
+
+impl PreviewState {
+    /// Run `f` on the decoded `path`, decoding it again only when it changed.
+    fn with_source<T>(
+        &self,
+        path: &str,
+        options: &CompressOptions,
+        f: impl FnOnce(&Source) -> T,
+    ) -> Result<T, String> {
+        // files compressed in place change under the same path
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let mut source = self.source.lock().unwrap();
+        let cached =
+            matches!(&*source, Some((time, source)) if source.path == path && *time == modified);
+        if !cached {
+            let opened = Source::open(path, options.detection, &options.limits)
+                .map_err(|err| err.to_string())?;
+            *source = Some((modified, opened));
+        }
+        // filled above when it wasn't cached
+        Ok(f(&source.as_ref().unwrap().1))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PreviewResult {
+    pub data_url: String,
+    pub width: u32,
+    pub height: u32,
+    pub predicted_size: u64,
+    pub metrics: ErrorStats,
+    pub region: Option<Region>,
+    pub region_metrics: Option<ErrorStats>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,41 +117,75 @@ pub struct EmitFileRequestBody {
     files: Option<FileList>,
     operation: EmitFileOperation,
     options: Option<CompressOptions>,
+    // share a total size across the files being compressed
+    budget: Option<SizeBudget>,
 }
 
 impl FileState {
     pub fn new(files: FileList) -> Self {
+        // presets from ashuk.toml and the user config, built-in ones when they can't be read
+        let config = std::env::current_dir()
+            .map_err(|err| err.to_string())
+            .and_then(|dir| Config::load(&dir).map_err(|err| err.to_string()))
+            .unwrap_or_else(|err| {
+                log::error!("{}", err);
+                Config::builtin()
+            });
+
         // set default option
         let option = get_compress_options_context().unwrap();
+        let options = CompressOptions {
+            extension: option[0].extension.clone(),
+            quality: Some(option[0].default_value),
+            ..Default::default()
+        };
+        let options = match config.default_preset() {
+            Some(preset) => preset.apply(&options),
+            None => options,
+        };
+
         Self {
             files: Mutex::new(files),
-            options: Mutex::new(CompressOptions {
-                extension: option[0].extension.clone(),
-                quality: Some(option[0].default_value),
-            }),
+            options: Mutex::new(options),
+            config: Mutex::new(config),
         }
     }
 
-    pub fn add_file(&self, file_path: &str) -> FileContext {
-        let mut files = self.files.lock().unwrap();
+    /// Add a file to the list, it fails when the file is gone or isn't a supported image.
+    pub fn add_file(&self, file_path: &str) -> Result<FileContext, String> {
         // init data
+        let format = ImageFormat::detect(&file_path, FormatDetection::Content)
+            .map_err(|err| err.to_string())?
+            .format;
+        let size = std::fs::metadata(&file_path)
+            .map_err(|err| format!("{}: {}", file_path, err))?
+            .len();
         let file = FileContext {
             status: Status::Initialized,
             input: InputResult {
                 path: file_path.to_string().clone(),
-                size: std::fs::metadata(&file_path)
-                    .expect("There is no file.")
-                    .len(),
-                extension: ImageFormat::from_path(&file_path)
-                    .unwrap()
-                    .get_representative_ext_str(),
+                size,
+                extension: format.get_representative_ext_str(),
+                quality: match format {
+                    ImageFormat::Jpeg => jpeg_quality::estimate_file(file_path),
+                    _ => None,
+                },
+                // decoded before locking, so files are classified in parallel
+                content: classify::classify(
+                    file_path,
+                    FormatDetection::Content,
+                    &self.get_options().limits,
+                )
+                .ok(),
             },
             output: None,
+            error: None,
         };
         // update hashmap
+        let mut files = self.files.lock().unwrap();
         files.entry(file_path.to_string()).or_insert(file.clone());
 
-        file
+        Ok(file)
     }
 
     pub fn update_file(&self, path: String, file_status: FileContext) -> FileContext {
@@ -89,9 +194,10 @@ impl FileState {
             status: file_status.status,
             input: file_status.input,
             output: file_status.output,
+            error: file_status.error,
         };
         // update hashmap
-        files.entry(path).or_insert(new_file.clone());
+        files.insert(path, new_file.clone());
 
         new_file
     }
@@ -111,65 +217,103 @@ impl FileState {
         *options = _options.unwrap();
     }
 
-    pub fn process_compress(
+    pub fn get_options(&self) -> CompressOptions {
+        let options = self.options.lock().unwrap();
+        options.clone()
+    }
+
+    pub fn get_presets(&self) -> BTreeMap<String, Preset> {
+        let config = self.config.lock().unwrap();
+        config.presets.clone()
+    }
+
+    pub fn apply_preset(&self, name: &str) -> Result<CompressOptions, String> {
+        let config = self.config.lock().unwrap();
+        let preset = config.preset(name).map_err(|err| err.to_string())?;
+
+        let mut options = self.options.lock().unwrap();
+        *options = preset.apply(&options);
+        Ok(options.clone())
+    }
+
+    /// Store the current options as a preset in the user config.
+    pub fn save_preset(&self, name: &str) -> Result<(), String> {
+        let preset = Preset::from_options(&self.get_options());
+
+        let path = Config::user_path().map_err(|err| err.to_string())?;
+        let mut user_config = if path.is_file() {
+            Config::from_file(&path).map_err(|err| err.to_string())?
+        } else {
+            Config::default()
+        };
+        user_config.presets.insert(name.to_string(), preset.clone());
+        user_config.save(&path).map_err(|err| err.to_string())?;
+
+        let mut config = self.config.lock().unwrap();
+        config.presets.insert(name.to_string(), preset);
+        Ok(())
+    }
+
+    pub fn start_compress(
         &self,
         app_handle: &tauri::AppHandle,
         emitter_name: &str,
         path: std::string::String,
         file: FileContext,
     ) {
-        // compress option
-        let options = self.options.lock().unwrap();
         // update state
         let updated_file = self.update_file(
-            path.clone(),
+            path,
             FileContext {
                 status: Status::Pending,
-                input: file.clone().input,
+                input: file.input,
                 output: None,
+                error: None,
             },
         );
         // notify to client for start compressing
         notify_file_to_client(&app_handle, &updated_file, emitter_name);
+    }
 
-        // compress image
-        let result = compress_to_target_extension(
-            &path,
-            CompressOptions {
-                extension: options.extension.clone(),
-                quality: options.quality,
-            },
-        );
-
-        if result.is_ok() {
-            // update state
-            let updated_file = self.update_file(
-                path,
-                FileContext {
-                    status: Status::Success,
-                    input: file.input,
-                    output: Some(result.unwrap()),
-                },
-            );
-            // notify to client for success
-            notify_file_to_client(&app_handle, &updated_file, emitter_name);
-        } else {
-            // handle error
-            let status = match &result.err().unwrap() {
-                CompressError::Unsupported(..) => Status::Unsupported,
-                _ => Status::Failed,
-            };
-            // update state
-            let updated_file = self.update_file(
-                path,
-                FileContext {
-                    status: status,
-                    input: file.input,
-                    output: None,
-                },
-            );
-            // notify to client for failure
-            notify_file_to_client(&app_handle, &updated_file, emitter_name);
+    pub fn finish_compress(
+        &self,
+        app_handle: &tauri::AppHandle,
+        emitter_name: &str,
+        path: std::string::String,
+        file: FileContext,
+        result: Result<CompressResult, CompressError>,
+    ) {
+        match result {
+            Ok(output) => {
+                // update state
+                let updated_file = self.update_file(
+                    path,
+                    FileContext {
+                        status: Status::Success,
+                        input: file.input,
+                        output: Some(output),
+                        error: None,
+                    },
+                );
+                // notify to client for success
+                notify_file_to_client(&app_handle, &updated_file, emitter_name);
+            }
+            Err(err) => {
+                // handle error
+                let status = err.status();
+                // update state
+                let updated_file = self.update_file(
+                    path,
+                    FileContext {
+                        status: status,
+                        input: file.input,
+                        output: None,
+                        error: Some(err.to_string()),
+                    },
+                );
+                // notify to client for failure
+                notify_file_to_client(&app_handle, &updated_file, emitter_name);
+            }
         }
     }
 }
@@ -199,7 +343,7 @@ fn get_compress_options_context() -> Result<Vec<CompressOptionsContext>, String>
 
 #[tauri::command]
 fn get_supported_extensions() -> Result<Vec<SupportedFormatMeta>, String> {
-    let extensions = ImageFormat::get_formats()
+    let extensions = ImageFormat::get_input_formats()
         .iter()
         .map(|x| {
             x.extensions_str().iter().map(move |y| SupportedFormatMeta {
@@ -211,14 +355,239 @@ fn get_supported_extensions() -> Result<Vec<SupportedFormatMeta>, String> {
     Ok(extensions)
 }
 
+#[tauri::command]
+fn get_presets(file_state: tauri::State<FileState>) -> Result<BTreeMap<String, Preset>, String> {
+    Ok(file_state.get_presets())
+}
+
+#[tauri::command]
+fn apply_preset(
+    file_state: tauri::State<FileState>,
+    name: String,
+) -> Result<CompressOptions, String> {
+    file_state.apply_preset(&name)
+}
+
+#[tauri::command]
+fn save_preset(file_state: tauri::State<FileState>, name: String) -> Result<(), String> {
+    file_state.save_preset(&name)
+}
+
+#[tauri::command]
+fn start_watch(
+    app_handle: tauri::AppHandle,
+    file_state: tauri::State<FileState>,
+    watch_state: tauri::State<WatchState>,
+    dirs: Vec<String>,
+) -> Result<(), String> {
+    let settings = WatchOptions::new(file_state.get_options());
+    let watcher = Watcher::start(&dirs, settings, move |item| {
+        let file_state = app_handle.state::<FileState>();
+        let path = item.path.to_string_lossy().to_string();
+        // watched files join the list as they get compressed, unless they are gone by now
+        match file_state.add_file(&path) {
+            Ok(file) => {
+                file_state.finish_compress(&app_handle, FILE_EMITTER_NAME, path, file, item.result)
+            }
+            Err(err) => log::error!("{}", err),
+        }
+    })
+    .map_err(|err| err.to_string())?;
+
+    // replacing the previous watcher stops it
+    *watch_state.watcher.lock().unwrap() = Some(watcher);
+    Ok(())
+}
+
+#[tauri::command]
+fn stop_watch(watch_state: tauri::State<WatchState>) -> Result<(), String> {
+    if let Some(watcher) = watch_state.watcher.lock().unwrap().take() {
+        watcher.stop();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn export_report(
+    file_state: tauri::State<FileState>,
+    path: String,
+    format: ReportFormat,
+) -> Result<(), String> {
+    let file = std::fs::File::create(&path).map_err(|err| err.to_string())?;
+    let mut writer = ReportWriter::new(std::io::BufWriter::new(file), format);
+
+    for (_, file) in file_state.get_files() {
+        let input = InputInfo {
+            format: ImageFormat::from_extension(&file.input.extension),
+            path: file.input.path,
+            size: file.input.size,
+            quality: file.input.quality,
+        };
+        writer
+            .write(ReportEntry::new(
+                input,
+                file.output.as_ref(),
+                file.status,
+                file.error,
+            ))
+            .map_err(|err| err.to_string())?;
+    }
+
+    writer.finish().map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+fn export_manifest(
+    file_state: tauri::State<FileState>,
+    path: String,
+    picture_path: Option<String>,
+    picture_options: Option<PictureOptions>,
+) -> Result<(), String> {
+    let mut manifest = Manifest::default();
+    let mut files = file_state.get_files().into_iter().collect::<Vec<_>>();
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (input, file) in files {
+        if let Some(output) = &file.output {
+            let entry =
+                ManifestEntry::from_result(&input, output).map_err(|err| err.to_string())?;
+            manifest.push(entry);
+        }
+    }
+
+    manifest
+        .write(std::path::Path::new(&path))
+        .map_err(|err| err.to_string())?;
+    if let Some(picture_path) = picture_path {
+        let snippets = manifest.pictures(&picture_options.unwrap_or_default());
+        std::fs::write(picture_path, snippets).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+/// Rotate or flip a jpg on its DCT blocks, without the loss of a re-encode.
+/// The default undoes the EXIF orientation and fails rather than trim partial edge blocks.
+#[tauri::command]
+fn transform_jpeg(
+    path: String,
+    options: Option<JpegTransformOptions>,
+) -> Result<CompressResult, String> {
+    compresser::transform_jpeg(&path, &options.unwrap_or_default()).map_err(|err| err.to_string())
+}
+
+/// Tile every file of the list into one image at `path`, using the per-format encoder settings of the current options.
+#[tauri::command]
+fn create_contact_sheet(
+    file_state: tauri::State<FileState>,
+    path: String,
+    sheet: Option<ContactSheet>,
+) -> Result<CompressResult, String> {
+    let mut files = file_state.get_files().into_keys().collect::<Vec<String>>();
+    files.sort();
+
+    let options = CompressOptions {
+        quality: None,
+        ..file_state.get_options()
+    };
+    sheet
+        .unwrap_or_else(|| ContactSheet {
+            limits: options.limits,
+            ..Default::default()
+        })
+        .write(&files, &path, &options)
+        .map_err(|err| err.to_string())
+}
+
+/// Compare `input.path` and `output.path` of a file. The heatmap and side-by-side images are
+/// written to the temp directory; the original and output paths can drive a slider in the UI.
+#[tauri::command]
+fn create_diff(
+    file_state: tauri::State<FileState>,
+    input_path: String,
+    output_path: String,
+    options: Option<DiffOptions>,
+) -> Result<DiffReport, String> {
+    let options = options.unwrap_or_else(|| DiffOptions {
+        limits: file_state.get_options().limits,
+        ..Default::default()
+    });
+    let diff = diff::compare(&input_path, &output_path, &options).map_err(|err| err.to_string())?;
+
+    let dir = std::env::temp_dir().join("ashuk-diff");
+    std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+    let stem = std::path::Path::new(&output_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    // outputs of the same name in different folders must not share images
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (&input_path, &output_path).hash(&mut hasher);
+    let name = format!("{}-{:016x}", stem, hasher.finish());
+    let heatmap_path = dir.join(format!("{}-heatmap.png", name));
+    let side_by_side_path = dir.join(format!("{}-compare.png", name));
+
+    // quick png encoding, these images are only looked at
+    let mut encode_options = CompressOptions::default();
+    encode_options.encoder.png.level = Some(1);
+    diff.write(
+        &heatmap_path.to_string_lossy(),
+        &side_by_side_path.to_string_lossy(),
+        &encode_options,
+    )
+    .map_err(|err| err.to_string())
+}
+
+/// Encode `path` in memory with the given or current options. Nothing is written to disk.
+#[tauri::command]
+fn preview_file(
+    file_state: tauri::State<FileState>,
+    preview_state: tauri::State<PreviewState>,
+    path: String,
+    options: Option<CompressOptions>,
+    region: Option<Region>,
+) -> Result<PreviewResult, String> {
+    let options = options.unwrap_or_else(|| file_state.get_options());
+    let preview = preview_state
+        .with_source(&path, &options, |source| source.preview(&options, region))?
+        .map_err(|err| err.to_string())?;
+    Ok(PreviewResult {
+        data_url: preview.data_url(),
+        width: preview.width,
+        height: preview.height,
+        predicted_size: preview.predicted_size,
+        metrics: preview.metrics,
+        region: preview.region,
+        region_metrics: preview.region_metrics,
+    })
+}
+
+/// Predicted sizes and SSIM across the quality range, for the quality slider.
+#[tauri::command]
+fn quality_curve(
+    file_state: tauri::State<FileState>,
+    preview_state: tauri::State<PreviewState>,
+    path: String,
+    options: Option<CompressOptions>,
+    curve: Option<CurveOptions>,
+) -> Result<QualityCurve, String> {
+    let options = options.unwrap_or_else(|| file_state.get_options());
+    let curve = curve.unwrap_or_default();
+    preview_state
+        .with_source(&path, &options, |source| {
+            source.quality_curve(&options, &curve)
+        })?
+        .map_err(|err| err.to_string())
+}
+
 fn compress_file_handler(app: &tauri::AppHandle) {
-    let emitter_name = "listen-file";
-    let file_state = FileState::new(HashMap::new());
+    let emitter_name = FILE_EMITTER_NAME;
     let app_handle = app.app_handle();
     // listen file input
     let emit_file_create = app_handle
         .app_handle()
         .listen_global("emit-file", move |event| {
+            let file_state = app_handle.state::<FileState>();
             // get task
             let task: EmitFileRequestBody = serde_json::from_str(&event.payload().unwrap())
                 .expect("JSON was not well-formatted");
@@ -228,59 +597,66 @@ fn compress_file_handler(app: &tauri::AppHandle) {
                 EmitFileOperation::Create => {
                     // check
                     if let Some(v) = &task.files {
-                        // process parallelly
-                        v.into_par_iter().for_each(|(path, _)| {
-                            let add_file = file_state.add_file(&path);
-                            notify_file_to_client(&app_handle, &add_file, emitter_name);
-                        });
+                        // expand directories and drop files that aren't supported images
+                        let files = Batch::new(CompressOptions::default())
+                            .paths(v.keys(), true)
+                            .collect_files();
+
+                        match files {
+                            Ok(files) => {
+                                // process parallelly
+                                files.into_par_iter().for_each(|path| {
+                                    match file_state.add_file(&path.to_string_lossy()) {
+                                        Ok(add_file) => notify_file_to_client(
+                                            &app_handle,
+                                            &add_file,
+                                            emitter_name,
+                                        ),
+                                        Err(err) => log::error!("{}", err),
+                                    }
+                                });
+                            }
+                            Err(err) => log::error!("{}", err),
+                        }
                     };
                 }
                 // compress
                 EmitFileOperation::Compress => {
                     // check
                     if let Some(v) = &task.files {
+                        let options = file_state.get_options();
+                        let mut jobs = v
+                            .iter()
+                            // skip compressed file
+                            .filter(|(_, file)| !matches!(file.status, Status::Success))
+                            .map(|(path, _)| Job::new(path, options.clone()))
+                            .collect::<Vec<Job>>();
+                        if let Some(budget) = &task.budget {
+                            let plan = size_budget::plan(&jobs, budget);
+                            plan.apply(&mut jobs);
+                            // quality given to each file, before any of them starts
+                            notify_file_to_client(&app_handle, &plan, "listen-budget");
+                        }
 
-                        // wheather process parallelly or not
-                        let paralell_processable =
-                            v.into_par_iter().all(|(path, _)|
-                            // process strategy with multithreading depends on it's library implementation
-                            matches!(
-                                ImageFormat::process_strategy(&ImageFormat::from_path(&path).unwrap()),
-                                ProcessStrategy::Parallel
-                            ));
-
-                        match paralell_processable {
-                            true => {
-                                // process parallelly
-                                v.clone()
-                                    .into_par_iter()
-                                    // skip compressed file
-                                    .filter(|(_, file)| !matches!(file.status, Status::Success))
-                                    .for_each(|(path, file)| {
-                                        file_state.process_compress(
-                                            &app_handle,
-                                            &emitter_name,
-                                            path,
-                                            file,
-                                        )
-                                    });
+                        // serial and parallel-safe encoders share the thread budget
+                        Scheduler::default().run(jobs, |event| match event {
+                            ScheduleEvent::Started(path) => {
+                                let path = path.to_string_lossy().to_string();
+                                let file = v[&path].clone();
+                                file_state.start_compress(&app_handle, &emitter_name, path, file);
                             }
-                            _ => {
-                                // process seriesly
-                                v.clone()
-                                    .into_iter()
-                                    // skip compressed file
-                                    .filter(|(_, file)| !matches!(file.status, Status::Success))
-                                    .for_each(|(path, file)| {
-                                        file_state.process_compress(
-                                            &app_handle,
-                                            &emitter_name,
-                                            path,
-                                            file,
-                                        )
-                                    });
+                            ScheduleEvent::Finished(item) => {
+                                let path = item.path.to_string_lossy().to_string();
+                                let file = v[&path].clone();
+                                file_state.finish_compress(
+                                    &app_handle,
+                                    &emitter_name,
+                                    path,
+                                    file,
+                                    item.result,
+                                );
                             }
-                        };
+                        });
                     };
                 }
                 EmitFileOperation::Update => {
@@ -288,7 +664,11 @@ fn compress_file_handler(app: &tauri::AppHandle) {
                 }
                 EmitFileOperation::Clear => {
                     file_state.clear();
-                    notify_file_to_client(&app_handle, &EmitFileOperation::Clear, "listen-clear-file");
+                    notify_file_to_client(
+                        &app_handle,
+                        &EmitFileOperation::Clear,
+                        "listen-clear-file",
+                    );
                 }
             }
         });
@@ -296,9 +676,24 @@ fn compress_file_handler(app: &tauri::AppHandle) {
 
 pub fn init_app() {
     tauri::Builder::default()
+        .manage(FileState::new(HashMap::new()))
+        .manage(WatchState::default())
+        .manage(PreviewState::default())
         .invoke_handler(tauri::generate_handler![
             get_supported_extensions,
             get_compress_options_context,
+            start_watch,
+            stop_watch,
+            export_report,
+            export_manifest,
+            create_contact_sheet,
+            create_diff,
+            transform_jpeg,
+            preview_file,
+            quality_curve,
+            get_presets,
+            apply_preset,
+            save_preset,
         ])
         .setup(|app| {
             compress_file_handler(&app.app_handle());
@@ -0,0 +1,289 @@
+use ashuk_core::transform::{AspectRatio, Crop, Gravity, Resize};
+use clap::Parser;
+
+use std::path::PathBuf;
+
+/// Compress jpg, png and webp images, and convert gif to animated webp.
+#[derive(Parser, Debug)]
+#[command(name = "ashuk", version)]
+pub struct Args {
+    /// Files or directories to compress
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Target format (jpg, png, webp, or auto for the smallest at --target-ssim). Each file keeps its own format when omitted
+    #[arg(short, long)]
+    pub format: Option<String>,
+
+    /// Encoder quality: 0-100 for jpg and webp, optimization level 0-6 for png
+    #[arg(short, long)]
+    pub quality: Option<f32>,
+
+    /// Write outputs into this directory instead of next to the inputs
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Append this to output file names, e.g. ".min" writes photo.min.jpg
+    #[arg(long, value_name = "SUFFIX")]
+    pub suffix: Option<String>,
+
+    /// Shrink to fit within WIDTHxHEIGHT, keeping the aspect ratio (1280x720, 1280x or x720)
+    #[arg(long, value_name = "SIZE", value_parser = parse_resize)]
+    pub resize: Option<Resize>,
+
+    /// Encode webp losslessly, --quality then trades speed for size
+    #[arg(long)]
+    pub webp_lossless: bool,
+
+    /// Rewrite jpg to jpg losslessly from the DCT coefficients, with optimized Huffman tables
+    #[arg(long)]
+    pub lossless_jpeg: bool,
+
+    /// Drop EXIF, ICC and comment markers from losslessly rewritten jpgs
+    #[arg(long, requires = "lossless_jpeg")]
+    pub strip_metadata: bool,
+
+    /// When a jpg would be re-encoded to jpg above the quality it was saved at: "clamp" to that quality,
+    /// "skip" and keep it, or "ignore" and use the requested quality (default)
+    #[arg(long, value_name = "POLICY", value_parser = ["clamp", "skip", "ignore"])]
+    pub source_quality: Option<String>,
+
+    /// SSIM every candidate format is matched to with --format auto (default 0.95)
+    #[arg(long, value_name = "SSIM")]
+    pub target_ssim: Option<f64>,
+
+    /// Crop before resizing: an aspect ratio with optional gravity or "smart" (16:9, 1:1:smart, 4:3:north-east),
+    /// or a rectangle x,y,width,height
+    #[arg(long, value_name = "CROP", value_parser = parse_crop)]
+    pub crop: Option<Crop>,
+
+    /// Watermark every output with this image, usually a PNG with alpha
+    #[arg(long, value_name = "FILE", conflicts_with = "watermark_text")]
+    pub watermark: Option<PathBuf>,
+
+    /// Watermark every output with this text
+    #[arg(long, value_name = "TEXT")]
+    pub watermark_text: Option<String>,
+
+    /// Watermark position: center, north, north-east, east, south-east (default), south, south-west, west or north-west
+    #[arg(long, value_name = "GRAVITY", value_parser = parse_gravity)]
+    pub watermark_position: Option<Gravity>,
+
+    /// Watermark opacity from 0 to 1 (default 0.5)
+    #[arg(long, value_name = "OPACITY")]
+    pub watermark_opacity: Option<f32>,
+
+    /// Watermark width as a fraction of the image width (default 0.2)
+    #[arg(long, value_name = "SCALE")]
+    pub watermark_scale: Option<f32>,
+
+    /// Write a responsive set with these widths, e.g. 320,640,1280
+    #[arg(long, value_name = "WIDTHS", value_delimiter = ',')]
+    pub widths: Vec<u32>,
+
+    /// Formats of the responsive set, e.g. webp,jpg (--format when omitted)
+    #[arg(long, value_name = "FORMATS", value_delimiter = ',')]
+    pub formats: Vec<String>,
+
+    /// Start from this preset; --format, --quality and --output override its settings
+    #[arg(short, long, value_name = "NAME")]
+    pub preset: Option<String>,
+
+    /// Read presets from this file instead of ashuk.toml and the user config
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Walk directories recursively
+    #[arg(short, long)]
+    pub recursive: bool,
+
+    /// Only compress files matching this glob, relative to the walked directory
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files matching this glob, relative to the walked directory
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Include dot files and dot directories
+    #[arg(long)]
+    pub hidden: bool,
+
+    /// Follow symbolic links while walking directories
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Trust file extensions instead of sniffing file contents
+    #[arg(long)]
+    pub detect_by_extension: bool,
+
+    /// Fit all outputs together into this many bytes (e.g. 5MB, 800KB), choosing the quality of every file
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "quality")]
+    pub budget: Option<u64>,
+
+    /// Reject inputs wider than this many pixels
+    #[arg(long, value_name = "PIXELS")]
+    pub max_width: Option<u32>,
+
+    /// Reject inputs taller than this many pixels
+    #[arg(long, value_name = "PIXELS")]
+    pub max_height: Option<u32>,
+
+    /// Reject inputs with more pixels than this, width × height (default 16384×16384)
+    #[arg(long, value_name = "PIXELS")]
+    pub max_pixels: Option<u64>,
+
+    /// Reject inputs whose decoded pixels take more than this (e.g. 1GB, default 512MB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_alloc: Option<u64>,
+
+    /// Reject animations with more frames than this
+    #[arg(long, value_name = "FRAMES")]
+    pub max_frames: Option<u32>,
+
+    /// Maximum number of threads used for compression
+    #[arg(short = 'j', long)]
+    pub threads: Option<usize>,
+
+    /// Skip files already compressed with the same options, using the user cache directory
+    #[arg(long)]
+    pub cache: bool,
+
+    /// Like --cache, with the cache stored in this file
+    #[arg(long, value_name = "FILE")]
+    pub cache_file: Option<PathBuf>,
+
+    /// Write a report of every file to this path, or to stdout with "-"
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Report format, inferred from the report file extension when omitted (ndjson otherwise)
+    #[arg(long, value_name = "FORMAT", value_parser = ["ndjson", "json", "csv"])]
+    pub report_format: Option<String>,
+
+    /// Write a JSON manifest of every output with its size, dimensions and content hash
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
+    /// Write <picture> snippets for every input to this file
+    #[arg(long, value_name = "FILE")]
+    pub picture: Option<PathBuf>,
+
+    /// URL prefix used in <picture> snippets instead of output paths, e.g. /assets/
+    #[arg(long, value_name = "URL")]
+    pub url_prefix: Option<String>,
+
+    /// Keep running and compress images created or changed in the given directories
+    #[arg(short, long)]
+    pub watch: bool,
+}
+
+fn parse_crop(value: &str) -> Result<Crop, String> {
+    let number = |part: &str| {
+        part.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("{} is not a number", part))
+    };
+
+    let rect = value.split(',').collect::<Vec<&str>>();
+    if rect.len() == 4 {
+        return Ok(Crop::Rect {
+            x: number(rect[0])?,
+            y: number(rect[1])?,
+            width: number(rect[2])?,
+            height: number(rect[3])?,
+        });
+    }
+
+    let parts = value.split(':').collect::<Vec<&str>>();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err("expected WIDTH:HEIGHT[:GRAVITY] or X,Y,WIDTH,HEIGHT".to_string());
+    }
+    let aspect = AspectRatio {
+        width: number(parts[0])?,
+        height: number(parts[1])?,
+    };
+
+    match parts.get(2).copied().unwrap_or("center") {
+        "smart" => Ok(Crop::Smart { aspect }),
+        gravity => Ok(Crop::Gravity {
+            aspect,
+            gravity: parse_gravity(gravity)?,
+        }),
+    }
+}
+
+fn parse_resize(value: &str) -> Result<Resize, String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| "expected WIDTHxHEIGHT, WIDTHx or xHEIGHT".to_string())?;
+    let side = |part: &str| match part.trim() {
+        "" => Ok(None),
+        part => part
+            .parse::<u32>()
+            .map(Some)
+            .map_err(|_| format!("{} is not a number", part)),
+    };
+
+    let resize = Resize {
+        max_width: side(width)?,
+        max_height: side(height)?,
+    };
+    if resize.max_width.is_none() && resize.max_height.is_none() {
+        return Err("expected a width, a height or both".to_string());
+    }
+    Ok(resize)
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("{} is not a size", value))?;
+
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1.0,
+        "K" | "KB" => 1024.0,
+        "M" | "MB" => 1024.0 * 1024.0,
+        "G" | "GB" => 1024.0 * 1024.0 * 1024.0,
+        other => return Err(format!("{} is not a size unit", other)),
+    };
+
+    Ok((number * multiplier).round() as u64)
+}
+
+fn parse_gravity(value: &str) -> Result<Gravity, String> {
+    Ok(match value {
+        "center" => Gravity::Center,
+        "north" => Gravity::North,
+        "north-east" => Gravity::NorthEast,
+        "east" => Gravity::East,
+        "south-east" => Gravity::SouthEast,
+        "south" => Gravity::South,
+        "south-west" => Gravity::SouthWest,
+        "west" => Gravity::West,
+        "north-west" => Gravity::NorthWest,
+        other => return Err(format!("{} is not a gravity", other)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_resize() {
+        let resize = |max_width, max_height| Resize {
+            max_width,
+            max_height,
+        };
+        assert_eq!(parse_resize("1280x720"), Ok(resize(Some(1280), Some(720))));
+        assert_eq!(parse_resize("1280x"), Ok(resize(Some(1280), None)));
+        assert_eq!(parse_resize("x720"), Ok(resize(None, Some(720))));
+        assert!(parse_resize("x").is_err());
+        assert!(parse_resize("1280").is_err());
+    }
+}
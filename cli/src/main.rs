@@ -0,0 +1,13 @@
+pub mod args;
+pub mod run;
+pub mod summary;
+
+use clap::Parser;
+
+use std::process;
+
+fn main() {
+    let args = args::Args::parse();
+
+    process::exit(run::run(args));
+}
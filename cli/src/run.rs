@@ -0,0 +1,383 @@
+use ashuk_core::{
+    auto::AUTO_EXTENSION,
+    batch::{Batch, HiddenPolicy, SymlinkPolicy},
+    cache::CacheLocation,
+    compresser::{CompressOptions, OutputLocation, Variants},
+    config::Config,
+    format_meta::{FormatDetection, ImageFormat},
+    jpeg_quality::SourceQuality,
+    jpegtran::JpegMarkers,
+    manifest::{Manifest, ManifestEntry, PictureOptions},
+    report::{InputInfo, ReportEntry, ReportFormat, ReportWriter},
+    scheduler::Scheduler,
+    size_budget::{self, SizeBudget},
+    watch::{WatchOptions, Watcher},
+    watermark::{Watermark, WatermarkSource},
+};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::args::Args;
+use crate::summary::{self, Row};
+
+/// every file was compressed or skipped by the cache
+pub const EXIT_SUCCESS: i32 = 0;
+/// at least one file failed or was unsupported
+pub const EXIT_FAILURE: i32 = 1;
+/// invalid arguments, or the batch couldn't be set up
+pub const EXIT_USAGE: i32 = 2;
+
+fn load_config(args: &Args) -> Result<Config, String> {
+    match &args.config {
+        Some(path) => {
+            let mut config = Config::builtin();
+            config.merge(Config::from_file(path).map_err(|err| err.to_string())?);
+            Ok(config)
+        }
+        None => {
+            let cwd = env::current_dir().map_err(|err| err.to_string())?;
+            Config::load(&cwd).map_err(|err| err.to_string())
+        }
+    }
+}
+
+fn compress_options(args: &Args) -> Result<CompressOptions, String> {
+    let auto = args.format.as_deref() == Some(AUTO_EXTENSION);
+    for format in args.format.iter().filter(|_| !auto).chain(&args.formats) {
+        if ImageFormat::from_extension(format).is_none() {
+            return Err(format!("{} is not a supported format", format));
+        }
+    }
+
+    let config = load_config(args)?;
+    let preset = match &args.preset {
+        Some(name) => Some(config.preset(name).map_err(|err| err.to_string())?),
+        None => config.default_preset(),
+    };
+
+    let base = CompressOptions {
+        detection: if args.detect_by_extension {
+            FormatDetection::Extension
+        } else {
+            FormatDetection::Content
+        },
+        ..Default::default()
+    };
+    let mut options = match preset {
+        Some(preset) => preset.apply(&base),
+        None => base,
+    };
+
+    if args.quality.is_some() {
+        options.quality = args.quality;
+    }
+    if let Some(format) = &args.format {
+        options.extension = format.clone();
+    }
+    if let Some(dir) = &args.output {
+        options.output = OutputLocation::Dir(dir.clone());
+    }
+    if args.suffix.is_some() {
+        options.suffix = args.suffix.clone();
+    }
+    if args.resize.is_some() {
+        options.resize = args.resize;
+    }
+    if args.webp_lossless {
+        options.encoder.webp.lossless = true;
+    }
+    if args.lossless_jpeg {
+        options.encoder.jpeg.lossless = true;
+    }
+    if args.strip_metadata {
+        options.encoder.jpeg.markers = JpegMarkers::None;
+    }
+    match args.source_quality.as_deref() {
+        Some("clamp") => options.source_quality = SourceQuality::Clamp,
+        Some("skip") => options.source_quality = SourceQuality::Skip,
+        Some("ignore") => options.source_quality = SourceQuality::Ignore,
+        _ => {}
+    }
+    if let Some(target_ssim) = args.target_ssim {
+        options.auto.target_ssim = target_ssim;
+    }
+    if args.crop.is_some() {
+        options.crop = args.crop;
+    }
+    let source = match (&args.watermark, &args.watermark_text) {
+        (Some(path), _) => Some(WatermarkSource::Image(path.clone())),
+        (None, Some(text)) => Some(WatermarkSource::Text(text.clone())),
+        (None, None) => None,
+    };
+    if let Some(source) = source {
+        options.watermark = Some(Watermark::new(source));
+    }
+    if let Some(watermark) = options.watermark.as_mut() {
+        if let Some(position) = args.watermark_position {
+            watermark.position = position;
+        }
+        if let Some(opacity) = args.watermark_opacity {
+            watermark.opacity = opacity;
+        }
+        if let Some(scale) = args.watermark_scale {
+            watermark.scale = scale;
+        }
+    }
+    if args.max_width.is_some() {
+        options.limits.max_width = args.max_width;
+    }
+    if args.max_height.is_some() {
+        options.limits.max_height = args.max_height;
+    }
+    if args.max_pixels.is_some() {
+        options.limits.max_pixels = args.max_pixels;
+    }
+    if args.max_alloc.is_some() {
+        options.limits.max_alloc = args.max_alloc;
+    }
+    if args.max_frames.is_some() {
+        options.limits.max_frames = args.max_frames;
+    }
+    if !args.widths.is_empty() || !args.formats.is_empty() {
+        options.variants = Some(Variants {
+            widths: args.widths.clone(),
+            formats: args.formats.clone(),
+        });
+    }
+
+    Ok(options)
+}
+
+fn report_writer(args: &Args) -> Result<Option<ReportWriter<Box<dyn Write>>>, String> {
+    let path = match &args.report {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let format = match args.report_format.as_deref() {
+        Some("json") => ReportFormat::Json,
+        Some("csv") => ReportFormat::Csv,
+        Some(_) => ReportFormat::Ndjson,
+        None => ReportFormat::from_path(path).unwrap_or(ReportFormat::Ndjson),
+    };
+
+    let writer: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        let file = File::create(path)
+            .map_err(|err| format!("failed to create {}: {}", path.to_string_lossy(), err))?;
+        Box::new(io::BufWriter::new(file))
+    };
+
+    Ok(Some(ReportWriter::new(writer, format)))
+}
+
+fn batch(args: &Args, options: CompressOptions) -> Batch {
+    let mut batch = Batch::new(options)
+        .paths(&args.paths, args.recursive)
+        .hidden(if args.hidden {
+            HiddenPolicy::Include
+        } else {
+            HiddenPolicy::Skip
+        })
+        .symlinks(if args.follow_symlinks {
+            SymlinkPolicy::Follow
+        } else {
+            SymlinkPolicy::Skip
+        });
+
+    for pattern in &args.include {
+        batch = batch.include(pattern);
+    }
+    for pattern in &args.exclude {
+        batch = batch.exclude(pattern);
+    }
+    if let Some(threads) = args.threads {
+        batch = batch.scheduler(Scheduler::new(threads));
+    }
+    if let Some(file) = &args.cache_file {
+        batch = batch.cache(CacheLocation::File(file.clone()));
+    } else if args.cache {
+        batch = batch.cache(CacheLocation::User);
+    }
+
+    batch
+}
+
+fn write_manifest(args: &Args, rows: &[Row]) -> Result<(), String> {
+    let mut manifest = Manifest::default();
+    for row in rows {
+        if let Some(Ok(output)) = row.item.as_ref().map(|item| &item.result) {
+            let input = row.path.to_string_lossy();
+            let entry = ManifestEntry::from_result(&input, output)
+                .map_err(|err| format!("failed to hash outputs of {}: {}", input, err))?;
+            manifest.push(entry);
+        }
+    }
+
+    if let Some(path) = &args.manifest {
+        manifest
+            .write(path)
+            .map_err(|err| format!("failed to write {}: {}", path.to_string_lossy(), err))?;
+    }
+    if let Some(path) = &args.picture {
+        let options = PictureOptions {
+            url_prefix: args.url_prefix.clone(),
+            ..Default::default()
+        };
+        std::fs::write(path, manifest.pictures(&options))
+            .map_err(|err| format!("failed to write {}: {}", path.to_string_lossy(), err))?;
+    }
+
+    Ok(())
+}
+
+pub fn run(args: Args) -> i32 {
+    let options = match compress_options(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return EXIT_USAGE;
+        }
+    };
+
+    if args.watch {
+        return watch(&args, options);
+    }
+
+    let mut report = match report_writer(&args) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return EXIT_USAGE;
+        }
+    };
+
+    let batch = batch(&args, options);
+    let mut jobs = match batch.jobs() {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return EXIT_USAGE;
+        }
+    };
+
+    let plan = args.budget.map(|total_bytes| {
+        let budget = SizeBudget {
+            total_bytes,
+            curve: Default::default(),
+        };
+        let plan = size_budget::plan(&jobs, &budget);
+        plan.apply(&mut jobs);
+        plan
+    });
+
+    // sizes and formats are taken up front, inputs may be overwritten in place
+    let mut inputs = jobs
+        .iter()
+        .map(|job| {
+            let input = InputInfo::read(&job.path, job.options.detection);
+            (job.path.clone(), input)
+        })
+        .collect::<HashMap<PathBuf, InputInfo>>();
+    let mut rows = jobs
+        .iter()
+        .map(|job| Row {
+            path: job.path.clone(),
+            input_size: inputs[&job.path].size,
+            item: None,
+        })
+        .collect::<Vec<Row>>();
+    let index = rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| (row.path.clone(), i))
+        .collect::<HashMap<PathBuf, usize>>();
+
+    let mut run = match batch.run_jobs(jobs) {
+        Ok(run) => run,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return EXIT_USAGE;
+        }
+    };
+
+    for item in run.by_ref() {
+        if let (Some(report), Some(input)) = (report.as_mut(), inputs.remove(&item.path)) {
+            if let Err(err) = report.write(ReportEntry::from_item(input, &item)) {
+                eprintln!("warning: failed to write report: {}", err);
+            }
+        }
+        if let Some(i) = index.get(&item.path) {
+            rows[*i].item = Some(item);
+        }
+    }
+    let cache_result = run.finish();
+
+    if let Some(Err(err)) = report.map(ReportWriter::finish) {
+        eprintln!("warning: failed to write report: {}", err);
+    }
+    if args.manifest.is_some() || args.picture.is_some() {
+        if let Err(err) = write_manifest(&args, &rows) {
+            eprintln!("warning: {}", err);
+        }
+    }
+
+    // keep stdout machine-readable when the report goes there
+    let mut rendered = summary::render(&rows);
+    if let Some(plan) = &plan {
+        rendered += &summary::render_budget(plan);
+    }
+    if args.report.as_deref() == Some(Path::new("-")) {
+        eprint!("{}", rendered);
+    } else {
+        print!("{}", rendered);
+    }
+
+    if let Err(err) = cache_result {
+        eprintln!("warning: {}", err);
+    }
+
+    let failed = rows
+        .iter()
+        .any(|row| !matches!(row.item.as_ref().map(|item| &item.result), Some(Ok(_))));
+    if failed {
+        EXIT_FAILURE
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+fn watch(args: &Args, options: CompressOptions) -> i32 {
+    let mut settings = WatchOptions::new(options);
+    settings.recursive = args.recursive;
+
+    let watcher = Watcher::start(&args.paths, settings, |item| match item.result {
+        Ok(output) => println!(
+            "{} -> {} ({})",
+            item.path.to_string_lossy(),
+            output.path,
+            summary::format_bytes(output.size)
+        ),
+        Err(err) => eprintln!("{}: {}", item.path.to_string_lossy(), err),
+    });
+
+    match watcher {
+        Ok(_watcher) => {
+            println!("watching for changes, press Ctrl+C to stop");
+            // the watcher works in the background until the process is interrupted
+            loop {
+                thread::park();
+            }
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            EXIT_USAGE
+        }
+    }
+}
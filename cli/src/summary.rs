@@ -0,0 +1,218 @@
+use ashuk_core::{
+    batch::BatchItem,
+    compresser::{CompressError, Status},
+    size_budget::BudgetPlan,
+};
+
+use std::path::PathBuf;
+
+pub struct Row {
+    pub path: PathBuf,
+    pub input_size: u64,
+    pub item: Option<BatchItem>,
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const SIZES: [&str; 5] = ["Bytes", "KB", "MB", "GB", "TB"];
+
+    if bytes == 0 {
+        return "0 Bytes".to_string();
+    }
+
+    let i = ((bytes as f64).ln() / 1024_f64.ln()).floor() as usize;
+    let i = i.min(SIZES.len() - 1);
+    let value = bytes as f64 / 1024_f64.powi(i as i32);
+
+    format!("{} {}", (value * 100.0).round() / 100.0, SIZES[i])
+}
+
+fn saved(before: u64, after: u64) -> String {
+    if before == 0 {
+        return "-".to_string();
+    }
+    let ratio = (1.0 - after as f64 / before as f64) * 100.0;
+    format!("{:.1}%", ratio)
+}
+
+fn status(error: &CompressError) -> &'static str {
+    match error.status() {
+        Status::Unsupported => "unsupported",
+        _ => "failed",
+    }
+}
+
+/// Render the size savings of a batch as a table, followed by why auto targets chose their format and the error of every failed file.
+pub fn render(rows: &[Row]) -> String {
+    let mut lines = vec![[
+        "File".to_string(),
+        "Before".to_string(),
+        "After".to_string(),
+        "Saved".to_string(),
+        "Time".to_string(),
+        "Status".to_string(),
+    ]];
+    let mut notes = Vec::new();
+    let mut errors = Vec::new();
+    let (mut total_before, mut total_after) = (0, 0);
+
+    for row in rows {
+        let file = row.path.to_string_lossy().to_string();
+        let before = format_bytes(row.input_size);
+        let line = match row.item.as_ref().map(|item| &item.result) {
+            Some(Ok(output)) => {
+                if let Some(selection) = &output.selection {
+                    notes.push(format!("{}: {}", file, selection.reason));
+                }
+                total_before += row.input_size;
+                let after = output.total_size();
+                total_after += after;
+                [
+                    file,
+                    before,
+                    format_bytes(after),
+                    saved(row.input_size, after),
+                    format!("{} ms", output.elapsed),
+                    match (output.cached, output.outputs.len()) {
+                        (true, _) => "cached".to_string(),
+                        (false, 0..=1) => "ok".to_string(),
+                        (false, count) => format!("ok ({} files)", count),
+                    },
+                ]
+            }
+            Some(Err(err)) => {
+                errors.push(format!("{}: {}", file, err));
+                let status = status(err).to_string();
+                [file, before, "-".into(), "-".into(), "-".into(), status]
+            }
+            None => [
+                file,
+                before,
+                "-".into(),
+                "-".into(),
+                "-".into(),
+                "skipped".into(),
+            ],
+        };
+        lines.push(line);
+    }
+
+    lines.push([
+        format!("Total ({} files)", rows.len()),
+        format_bytes(total_before),
+        format_bytes(total_after),
+        saved(total_before, total_after),
+        String::new(),
+        String::new(),
+    ]);
+
+    let mut widths = [0; 6];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut rendered = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        // separate header and total from the file rows
+        if i == 1 || i == lines.len() - 1 {
+            let rule = widths.iter().map(|width| "-".repeat(*width));
+            rendered += &rule.collect::<Vec<String>>().join("  ");
+            rendered += "\n";
+        }
+        let cells = line
+            .iter()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(j, (cell, width))| {
+                // numbers are right aligned
+                if (1..5).contains(&j) {
+                    format!("{:>width$}", cell, width = width)
+                } else {
+                    format!("{:<width$}", cell, width = width)
+                }
+            });
+        rendered += cells.collect::<Vec<String>>().join("  ").trim_end();
+        rendered += "\n";
+    }
+    for note in notes {
+        rendered += &note;
+        rendered += "\n";
+    }
+    for error in errors {
+        rendered += &error;
+        rendered += "\n";
+    }
+
+    rendered
+}
+
+/// Render the quality chosen for every file of a size budget.
+pub fn render_budget(plan: &BudgetPlan) -> String {
+    let estimated = plan
+        .allocations
+        .iter()
+        .all(|allocation| allocation.quality.is_some());
+    let at_minimum = plan
+        .allocations
+        .iter()
+        .all(|allocation| allocation.at_minimum);
+    let over = match (plan.fits, estimated, at_minimum) {
+        (true, _, _) => "",
+        (false, false, _) => ", over or unknown with the files that couldn't be estimated",
+        (false, true, true) => ", over even at the lowest quality",
+        (false, true, false) => ", over after the last planning pass",
+    };
+    let mut rendered = format!(
+        "Budget: {} of {}{}\n",
+        format_bytes(plan.planned_bytes),
+        format_bytes(plan.total_bytes),
+        over
+    );
+    for allocation in &plan.allocations {
+        let path = allocation.path.to_string_lossy();
+        rendered += &match (allocation.quality, allocation.ssim, &allocation.error) {
+            (Some(quality), Some(ssim), None) => {
+                format!("  {}: quality {}, SSIM {:.3}\n", path, quality, ssim)
+            }
+            (_, _, Some(error)) => format!("  {}: not estimated, {}\n", path, error),
+            _ => format!("  {}: not estimated\n", path),
+        };
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ashuk_core::size_budget::Allocation;
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 Bytes");
+        assert_eq!(format_bytes(1000), "1000 Bytes");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3 MB");
+    }
+
+    #[test]
+    fn test_render_budget_over() {
+        let allocation = |at_minimum| Allocation {
+            path: PathBuf::from("a.jpg"),
+            quality: Some(40.0),
+            bytes: Some(600),
+            ssim: Some(0.9),
+            at_minimum,
+            error: None,
+        };
+        let plan = |at_minimum| BudgetPlan {
+            total_bytes: 500,
+            planned_bytes: 600,
+            fits: false,
+            allocations: vec![allocation(at_minimum)],
+        };
+
+        assert!(render_budget(&plan(true)).contains("over even at the lowest quality"));
+        assert!(!render_budget(&plan(false)).contains("lowest quality"));
+    }
+}
@@ -0,0 +1,11 @@
+use std::env;
+
+fn main() {
+    // jpegtran.c is built against the same libjpeg headers mozjpeg-sys compiles
+    let include = env::var_os("DEP_JPEG_INCLUDE").expect("mozjpeg-sys exports its include dirs");
+    cc::Build::new()
+        .file("src/jpegtran.c")
+        .includes(env::split_paths(&include))
+        .compile("ashuk_jpegtran");
+    println!("cargo:rerun-if-changed=src/jpegtran.c");
+}
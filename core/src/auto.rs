@@ -0,0 +1,231 @@
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+use std::result;
+
+use crate::compresser::{CompressError, CompressOptions};
+use crate::curve::{self, CurveOptions};
+use crate::encoder;
+use crate::format_meta::ImageFormat;
+
+/// `CompressOptions::extension` that picks the smallest format for every file.
+pub const AUTO_EXTENSION: &str = "auto";
+
+// samples per candidate when matching the target SSIM
+const MATCH_STEPS: u32 = 21;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct AutoOptions {
+    /// every lossy candidate is encoded at the lowest quality reaching this SSIM
+    pub target_ssim: f64,
+    /// extensions to try, every format the source can be compressed to when empty
+    pub candidates: Vec<String>,
+}
+
+impl Default for AutoOptions {
+    fn default() -> Self {
+        Self {
+            target_ssim: 0.95,
+            candidates: Vec::new(),
+        }
+    }
+}
+
+impl AutoOptions {
+    /// Formats tried for a `source` file. A candidate that isn't a known extension is an error.
+    pub fn candidates(
+        &self,
+        source: ImageFormat,
+    ) -> result::Result<Vec<ImageFormat>, CompressError> {
+        let formats = if self.candidates.is_empty() {
+            ImageFormat::get_formats()
+        } else {
+            self.candidates
+                .iter()
+                .map(|extension| {
+                    ImageFormat::from_extension(extension)
+                        .ok_or_else(|| CompressError::UnsupportedExtension(extension.clone()))
+                })
+                .collect::<result::Result<Vec<ImageFormat>, CompressError>>()?
+        };
+
+        Ok(formats
+            .into_iter()
+            .filter(|format| source.can_compress(format))
+            .collect())
+    }
+}
+
+/// One format tried by an auto selection.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Candidate {
+    pub format: ImageFormat,
+    pub quality: f32,
+    pub bytes: u64,
+    /// estimated, 1 for lossless encodes
+    pub ssim: f64,
+}
+
+/// Why an auto target chose its format.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Selection {
+    /// the winner, also one of `candidates`
+    pub chosen: Candidate,
+    pub candidates: Vec<Candidate>,
+    pub reason: String,
+}
+
+fn is_lossless(format: ImageFormat, options: &CompressOptions) -> bool {
+    match format {
+        ImageFormat::Png => true,
+        ImageFormat::WebP => options.encoder.webp.lossless,
+        _ => false,
+    }
+}
+
+// lowest quality of `format` reaching the target, or the most faithful one sampled
+fn matched_quality(
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &CompressOptions,
+) -> result::Result<(f32, f64), CompressError> {
+    if is_lossless(format, options) {
+        return Ok((options.quality_for(format), 1.0));
+    }
+
+    let curve = curve::quality_curve(
+        image,
+        format,
+        options,
+        &CurveOptions {
+            steps: MATCH_STEPS,
+            ..Default::default()
+        },
+    )?;
+    let target = options.auto.target_ssim;
+    let point = curve
+        .points
+        .iter()
+        .filter(|point| point.ssim >= target)
+        .min_by(|a, b| a.quality.partial_cmp(&b.quality).unwrap_or(Ordering::Equal))
+        .or_else(|| {
+            curve
+                .points
+                .iter()
+                .max_by(|a, b| a.ssim.partial_cmp(&b.ssim).unwrap_or(Ordering::Equal))
+        });
+
+    Ok(point.map_or((options.quality_for(format), 0.0), |point| {
+        (point.quality, point.ssim)
+    }))
+}
+
+fn reason(chosen: &Candidate, candidates: &[Candidate], target_ssim: f64) -> String {
+    let runner_up = candidates
+        .iter()
+        .filter(|candidate| candidate.format != chosen.format)
+        .min_by_key(|candidate| candidate.bytes);
+
+    match runner_up {
+        Some(runner_up) => format!(
+            "{} at quality {} is {:.0}% smaller than {} at quality {}, both aiming for SSIM {}",
+            chosen.format,
+            chosen.quality,
+            (1.0 - chosen.bytes as f64 / runner_up.bytes.max(1) as f64) * 100.0,
+            runner_up.format,
+            runner_up.quality,
+            target_ssim,
+        ),
+        None => format!("{} is the only candidate", chosen.format),
+    }
+}
+
+/// Encode `image`, already transformed, in every candidate format at matched SSIM and keep the smallest.
+pub fn select(
+    image: &DynamicImage,
+    source: ImageFormat,
+    options: &CompressOptions,
+) -> result::Result<(Selection, Vec<u8>), CompressError> {
+    let formats = options.auto.candidates(source)?;
+    if formats.is_empty() {
+        return Err(CompressError::UnsupportedExtension(
+            options.auto.candidates.join(","),
+        ));
+    }
+
+    let mut candidates = Vec::new();
+    let mut smallest: Option<(usize, Vec<u8>)> = None;
+    for format in formats {
+        let (quality, ssim) = matched_quality(image, format, options)?;
+        let encoded = encoder::encode(
+            image,
+            format,
+            &CompressOptions {
+                quality: Some(quality),
+                ..options.clone()
+            },
+            "auto",
+        )?;
+
+        candidates.push(Candidate {
+            format,
+            quality,
+            bytes: encoded.len() as u64,
+            ssim,
+        });
+        if smallest
+            .as_ref()
+            .map_or(true, |(_, bytes)| encoded.len() < bytes.len())
+        {
+            smallest = Some((candidates.len() - 1, encoded));
+        }
+    }
+
+    // at least one candidate was encoded
+    let (index, encoded) = smallest.unwrap();
+    let chosen = candidates[index].clone();
+    Ok((
+        Selection {
+            reason: reason(&chosen, &candidates, options.auto.target_ssim),
+            chosen,
+            candidates,
+        },
+        encoded,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_smallest_candidate() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 150, |x, y| {
+            image::Rgb([(x + y) as u8, (x * 2) as u8, (y * 3) as u8])
+        }));
+        let options = CompressOptions {
+            extension: AUTO_EXTENSION.to_string(),
+            ..Default::default()
+        };
+
+        let (selection, encoded) = select(&image, ImageFormat::Jpeg, &options).unwrap();
+        assert_eq!(selection.candidates.len(), 2);
+        assert_eq!(selection.chosen.bytes, encoded.len() as u64);
+        assert!(selection
+            .candidates
+            .iter()
+            .all(|candidate| candidate.bytes >= selection.chosen.bytes));
+        assert!(selection.reason.contains("smaller than"));
+
+        let misspelled = AutoOptions {
+            candidates: vec!["webp".to_string(), "jpgg".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            misspelled.candidates(ImageFormat::Jpeg),
+            Err(CompressError::UnsupportedExtension(extension)) if extension == "jpgg"
+        ));
+    }
+}
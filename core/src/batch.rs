@@ -0,0 +1,630 @@
+use glob::{MatchOptions, Pattern, PatternError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use walkdir::{DirEntry, WalkDir};
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::{mpsc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::cache::{hash_file, Cache, CacheError, CacheLocation};
+use crate::compresser::{self, CompressError, CompressOptions, OutputLocation, Result};
+use crate::format_meta::ImageFormat;
+use crate::scheduler::{Job, ScheduleEvent, Scheduler};
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("invalid glob pattern {pattern}: {source}")]
+    Pattern {
+        pattern: String,
+        #[source]
+        source: PatternError,
+    },
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to walk {path}: {source}")]
+    Walk {
+        path: String,
+        #[source]
+        source: walkdir::Error,
+    },
+    #[error("{first} and {second} would both be written as {output}")]
+    OutputCollision {
+        first: String,
+        second: String,
+        output: String,
+    },
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+}
+
+/// What to do with symbolic links found while walking a directory.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum SymlinkPolicy {
+    #[default]
+    Skip,
+    Follow,
+}
+
+/// What to do with dot files and dot directories found while walking a directory.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum HiddenPolicy {
+    #[default]
+    Skip,
+    Include,
+}
+
+#[derive(Debug, Clone)]
+enum Input {
+    File(PathBuf),
+    Dir { path: PathBuf, recursive: bool },
+    // file or directory, resolved when the batch is collected
+    Path { path: PathBuf, recursive: bool },
+}
+
+#[derive(Debug)]
+pub struct BatchItem {
+    pub path: PathBuf,
+    pub result: result::Result<Result, CompressError>,
+}
+
+/// Collects input files from paths and directories and compresses them with shared options.
+///
+/// Files added explicitly are always kept unless an exclude pattern matches them,
+/// files found in directories must also be a supported image and pass the symlink and hidden policies.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    options: CompressOptions,
+    inputs: Vec<Input>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    symlinks: SymlinkPolicy,
+    hidden: HiddenPolicy,
+    scheduler: Scheduler,
+    cache: Option<CacheLocation>,
+}
+
+impl Batch {
+    pub fn new(options: CompressOptions) -> Self {
+        Self {
+            options,
+            inputs: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            symlinks: SymlinkPolicy::default(),
+            hidden: HiddenPolicy::default(),
+            scheduler: Scheduler::default(),
+            cache: None,
+        }
+    }
+
+    pub fn file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.inputs.push(Input::File(path.into()));
+        self
+    }
+
+    pub fn files<I, P>(self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        paths.into_iter().fold(self, |batch, path| batch.file(path))
+    }
+
+    pub fn dir<P: Into<PathBuf>>(mut self, path: P, recursive: bool) -> Self {
+        self.inputs.push(Input::Dir {
+            path: path.into(),
+            recursive,
+        });
+        self
+    }
+
+    /// Add a file or a directory, whichever the path turns out to be.
+    pub fn path<P: Into<PathBuf>>(mut self, path: P, recursive: bool) -> Self {
+        self.inputs.push(Input::Path {
+            path: path.into(),
+            recursive,
+        });
+        self
+    }
+
+    pub fn paths<I, P>(self, paths: I, recursive: bool) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        paths
+            .into_iter()
+            .fold(self, |batch, path| batch.path(path, recursive))
+    }
+
+    /// Keep only files whose path relative to the walked directory matches the glob.
+    pub fn include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Drop files whose path relative to the walked directory matches the glob.
+    pub fn exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    pub fn hidden(mut self, policy: HiddenPolicy) -> Self {
+        self.hidden = policy;
+        self
+    }
+
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Skip files already compressed with the same options and record new results in the cache.
+    pub fn cache(mut self, location: CacheLocation) -> Self {
+        self.cache = Some(location);
+        self
+    }
+
+    pub fn options(&self) -> &CompressOptions {
+        &self.options
+    }
+
+    /// Resolve every input into the list of files that will be compressed, in input order and without duplicates.
+    pub fn collect_files(&self) -> result::Result<Vec<PathBuf>, BatchError> {
+        Ok(self
+            .collect_entries()?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect())
+    }
+
+    /// Jobs for the collected files. With an output directory, the layout of walked directories is mirrored in it.
+    ///
+    /// Files that may be written to the same output, e.g. `photo.png` and `photo.jpg` both converted
+    /// to webp, are an error instead of overwriting each other.
+    pub fn jobs(&self) -> result::Result<Vec<Job>, BatchError> {
+        let entries = self.collect_entries()?;
+
+        let mut outputs = HashMap::new();
+        let mut jobs = Vec::new();
+        for (path, relative_dir) in entries {
+            let mut options = self.options.clone();
+            if let (OutputLocation::Dir(dir), Some(relative_dir)) =
+                (&self.options.output, relative_dir)
+            {
+                options.output = OutputLocation::Dir(dir.join(relative_dir));
+            }
+            let job = Job::new(path, options);
+
+            // predicted from the routed options, files whose outputs can't be fail on their own later
+            let paths = compresser::output_paths(&job.path.to_string_lossy(), &job.options)
+                .unwrap_or_default();
+            for output in paths {
+                match outputs.insert(output.clone(), job.path.clone()) {
+                    Some(first) if first != job.path => {
+                        return Err(BatchError::OutputCollision {
+                            first: first.to_string_lossy().to_string(),
+                            second: job.path.to_string_lossy().to_string(),
+                            output,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            jobs.push(job);
+        }
+
+        Ok(jobs)
+    }
+
+    // each file with its parent directory relative to the walked directory it was found in
+    fn collect_entries(&self) -> result::Result<Vec<(PathBuf, Option<PathBuf>)>, BatchError> {
+        let filter = Filter::new(&self.include, &self.exclude)?;
+
+        let mut seen = HashSet::new();
+        let mut files = Vec::new();
+        let mut push = |path: PathBuf, relative_dir: Option<PathBuf>| {
+            if seen.insert(path.clone()) {
+                files.push((path, relative_dir));
+            }
+        };
+
+        for input in &self.inputs {
+            match input {
+                Input::File(path) => {
+                    if filter.is_excluded(path) {
+                        continue;
+                    }
+                    push(path.clone(), None);
+                }
+                Input::Dir { path, recursive } => {
+                    for (file, relative_dir) in self.walk(path, *recursive, &filter)? {
+                        push(file, Some(relative_dir));
+                    }
+                }
+                Input::Path { path, recursive } => {
+                    let metadata = path.metadata().map_err(|source| BatchError::Io {
+                        path: path.to_string_lossy().to_string(),
+                        source,
+                    })?;
+                    if metadata.is_dir() {
+                        for (file, relative_dir) in self.walk(path, *recursive, &filter)? {
+                            push(file, Some(relative_dir));
+                        }
+                    } else if !filter.is_excluded(path) {
+                        push(path.clone(), None);
+                    }
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Compress the collected files in the background, yielding results in completion order.
+    pub fn run(self) -> result::Result<BatchRun, BatchError> {
+        let jobs = self.jobs()?;
+        self.run_jobs(jobs)
+    }
+
+    /// Like `run`, for jobs from `jobs` that were adjusted by the caller.
+    pub fn run_jobs(self, jobs: Vec<Job>) -> result::Result<BatchRun, BatchError> {
+        let remaining = jobs.len();
+        let scheduler = self.scheduler;
+        let cache = match &self.cache {
+            Some(location) => Some(Cache::open(location)?),
+            None => None,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let sender = Mutex::new(sender);
+            // the receiver may be dropped early, the rest of the batch still runs to completion
+            let send = |item: BatchItem| {
+                let _ = sender.lock().unwrap().send(item);
+            };
+
+            let cache = match cache {
+                Some(cache) => cache,
+                None => {
+                    scheduler.run(jobs, |event| {
+                        if let ScheduleEvent::Finished(item) = event {
+                            send(item);
+                        }
+                    });
+                    return Ok(());
+                }
+            };
+
+            // hash before compressing, inputs may be overwritten in place
+            let mut input_hashes = HashMap::new();
+            let mut pending = Vec::new();
+            for job in jobs {
+                let input_hash = match hash_file(&job.path) {
+                    Ok(hash) => hash,
+                    // let the compression report the error
+                    Err(_) => {
+                        pending.push(job);
+                        continue;
+                    }
+                };
+                match cache.lookup(&job.path, &input_hash, &job.options) {
+                    Some(entry) => send(BatchItem {
+                        path: job.path,
+                        result: Ok(Result::from_cache(entry)),
+                    }),
+                    None => {
+                        input_hashes.insert(job.path.clone(), (input_hash, job.options.clone()));
+                        pending.push(job);
+                    }
+                }
+            }
+
+            let cache = Mutex::new(cache);
+            scheduler.run(pending, |event| {
+                if let ScheduleEvent::Finished(item) = event {
+                    if let (Ok(output), Some((input_hash, options))) =
+                        (&item.result, input_hashes.get(&item.path))
+                    {
+                        // a result that can't be hashed is simply not cached
+                        let _ = cache.lock().unwrap().record(
+                            &item.path,
+                            input_hash.clone(),
+                            options,
+                            output,
+                        );
+                    }
+                    send(item);
+                }
+            });
+
+            let cache = cache.into_inner().unwrap();
+            cache.save()
+        });
+
+        Ok(BatchRun {
+            receiver,
+            remaining,
+            handle: Some(handle),
+        })
+    }
+
+    fn walk(
+        &self,
+        root: &Path,
+        recursive: bool,
+        filter: &Filter,
+    ) -> result::Result<Vec<(PathBuf, PathBuf)>, BatchError> {
+        let hidden = self.hidden;
+        let follow = matches!(self.symlinks, SymlinkPolicy::Follow);
+        let walker = WalkDir::new(root)
+            .follow_links(follow)
+            .max_depth(if recursive { usize::MAX } else { 1 })
+            .sort_by_file_name()
+            .into_iter()
+            // the root itself is never treated as hidden
+            .filter_entry(move |entry| {
+                entry.depth() == 0 || matches!(hidden, HiddenPolicy::Include) || !is_hidden(entry)
+            });
+
+        let mut files = Vec::new();
+        for entry in walker {
+            let entry = entry.map_err(|source| BatchError::Walk {
+                path: root.to_string_lossy().to_string(),
+                source,
+            })?;
+
+            // followed links report their target's file type but are still flagged as links
+            if !entry.file_type().is_file() || (!follow && entry.path_is_symlink()) {
+                continue;
+            }
+
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            if !filter.is_included(relative) || filter.is_excluded(relative) {
+                continue;
+            }
+
+            if ImageFormat::detect(entry.path(), self.options.detection).is_err() {
+                continue;
+            }
+
+            let relative_dir = relative.parent().map(Path::to_path_buf).unwrap_or_default();
+            files.push((entry.into_path(), relative_dir));
+        }
+
+        Ok(files)
+    }
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(false)
+}
+
+struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    fn new(include: &[String], exclude: &[String]) -> result::Result<Self, BatchError> {
+        fn compile(patterns: &[String]) -> result::Result<Vec<Pattern>, BatchError> {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Pattern::new(pattern).map_err(|source| BatchError::Pattern {
+                        pattern: pattern.clone(),
+                        source,
+                    })
+                })
+                .collect()
+        }
+
+        Ok(Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, MATCH_OPTIONS))
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| pattern.matches_path_with(path, MATCH_OPTIONS))
+    }
+}
+
+// `*` also crosses directories, so `*.png` matches at any depth
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+pub struct BatchRun {
+    receiver: mpsc::Receiver<BatchItem>,
+    remaining: usize,
+    handle: Option<JoinHandle<result::Result<(), CacheError>>>,
+}
+
+impl BatchRun {
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Wait for the batch to complete and report whether the cache could be saved.
+    pub fn finish(mut self) -> result::Result<(), BatchError> {
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(saved)) => saved.map_err(BatchError::from),
+            // a panicking worker already lost its results, there is nothing left to save
+            Some(Err(_)) | None => Ok(()),
+        }
+    }
+}
+
+impl Iterator for BatchRun {
+    type Item = BatchItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.receiver.recv().ok()?;
+        self.remaining -= 1;
+
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::ConversionRule;
+    use std::fs;
+
+    fn fixture(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        let asset = "src/assets/New-York-street-scene-with-steam-and-billboards.webp";
+        fs::create_dir_all(root.join("nested")).unwrap();
+        fs::create_dir_all(root.join(".hidden")).unwrap();
+        fs::copy(asset, root.join("a.webp")).unwrap();
+        fs::copy(asset, root.join("nested/b.webp")).unwrap();
+        fs::copy(asset, root.join(".hidden/c.webp")).unwrap();
+        fs::write(root.join("notes.txt"), "not an image").unwrap();
+        root
+    }
+
+    #[test]
+    fn test_collect_files() {
+        let root = fixture("ashuk-batch-collect");
+
+        let flat = Batch::new(CompressOptions::default())
+            .dir(&root, false)
+            .collect_files()
+            .unwrap();
+        assert_eq!(flat, vec![root.join("a.webp")]);
+
+        let recursive = Batch::new(CompressOptions::default())
+            .dir(&root, true)
+            .collect_files()
+            .unwrap();
+        assert_eq!(
+            recursive,
+            vec![root.join("a.webp"), root.join("nested/b.webp")]
+        );
+
+        let filtered = Batch::new(CompressOptions::default())
+            .path(&root, true)
+            .hidden(HiddenPolicy::Include)
+            .exclude("nested/*")
+            .collect_files()
+            .unwrap();
+        assert_eq!(
+            filtered,
+            vec![root.join(".hidden/c.webp"), root.join("a.webp")]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() {
+        let root = fixture("ashuk-batch-symlinks");
+        std::os::unix::fs::symlink(root.join("a.webp"), root.join("link.webp")).unwrap();
+
+        let skipped = Batch::new(CompressOptions::default())
+            .dir(&root, false)
+            .collect_files()
+            .unwrap();
+        assert_eq!(skipped, vec![root.join("a.webp")]);
+
+        let followed = Batch::new(CompressOptions::default())
+            .dir(&root, false)
+            .symlinks(SymlinkPolicy::Follow)
+            .collect_files()
+            .unwrap();
+        assert_eq!(followed, vec![root.join("a.webp"), root.join("link.webp")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_output_collision() {
+        let root = fixture("ashuk-batch-collision");
+        fs::copy(root.join("nested/b.webp"), root.join("b.webp")).unwrap();
+        let options = CompressOptions {
+            extension: "png".to_string(),
+            output: OutputLocation::Dir(root.join("out")),
+            ..Default::default()
+        };
+
+        let walked = Batch::new(options.clone()).dir(&root, true).jobs().unwrap();
+        assert_eq!(walked.len(), 3);
+
+        let explicit = Batch::new(options)
+            .file(root.join("b.webp"))
+            .file(root.join("nested/b.webp"))
+            .jobs();
+        assert!(matches!(explicit, Err(BatchError::OutputCollision { .. })));
+
+        // next to the inputs, two sources converted to the same format collide too
+        image::RgbImage::from_pixel(4, 4, image::Rgb([1, 2, 3]))
+            .save(root.join("b.png"))
+            .unwrap();
+        let converted = CompressOptions {
+            extension: "webp".to_string(),
+            ..Default::default()
+        };
+        let same_dir = Batch::new(converted.clone())
+            .file(root.join("b.png"))
+            .file(root.join("b.webp"))
+            .jobs();
+        assert!(matches!(same_dir, Err(BatchError::OutputCollision { .. })));
+
+        // routed to different formats they don't
+        let routed = Batch::new(CompressOptions {
+            rules: vec![ConversionRule::new("png", "png")],
+            ..converted.clone()
+        })
+        .file(root.join("b.png"))
+        .file(root.join("b.webp"))
+        .jobs();
+        assert!(routed.is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let result = Batch::new(CompressOptions::default())
+            .include("[")
+            .collect_files();
+        assert!(matches!(result, Err(BatchError::Pattern { .. })));
+    }
+}
@@ -0,0 +1,391 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use crate::compresser::{CompressOptions, Output, Result};
+use crate::watermark::WatermarkSource;
+
+const CACHE_FILE_NAME: &str = ".ashuk-cache.json";
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("failed to access cache {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("cache {path} is corrupted: {source}")]
+    Parse {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("user cache directory is not available on this platform")]
+    NoCacheDir,
+}
+
+/// Where the cache file is stored.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum CacheLocation {
+    /// `.ashuk-cache.json` inside the given directory
+    Directory(PathBuf),
+    /// `ashuk/cache.json` inside the user's cache directory
+    User,
+    File(PathBuf),
+}
+
+impl CacheLocation {
+    pub fn resolve(&self) -> result::Result<PathBuf, CacheError> {
+        match self {
+            CacheLocation::Directory(dir) => Ok(dir.join(CACHE_FILE_NAME)),
+            CacheLocation::User => dirs::cache_dir()
+                .map(|dir| dir.join("ashuk").join("cache.json"))
+                .ok_or(CacheError::NoCacheDir),
+            CacheLocation::File(path) => Ok(path.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub input_hash: String,
+    pub options_hash: String,
+    pub output_path: String,
+    pub output_hash: String,
+    pub output_size: u64,
+    pub extension: String,
+    #[serde(default)]
+    pub outputs: Vec<Output>,
+    /// hash of every written file by path, including the first output
+    #[serde(default)]
+    pub output_hashes: BTreeMap<String, String>,
+}
+
+impl CacheEntry {
+    // entries written before every output was hashed only know the first one
+    fn hashes(&self) -> Vec<(&str, &str)> {
+        if self.output_hashes.is_empty() {
+            return vec![(self.output_path.as_str(), self.output_hash.as_str())];
+        }
+        self.output_hashes
+            .iter()
+            .map(|(path, hash)| (path.as_str(), hash.as_str()))
+            .collect()
+    }
+}
+
+/// Records which files were already compressed with which options, keyed by canonical input path.
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+impl Cache {
+    /// Load the cache, starting empty when the file doesn't exist yet.
+    pub fn open(location: &CacheLocation) -> result::Result<Self, CacheError> {
+        let path = location.resolve()?;
+
+        let entries = match fs::read(&path) {
+            Ok(contents) => {
+                serde_json::from_slice(&contents).map_err(|source| CacheError::Parse {
+                    path: path.to_string_lossy().to_string(),
+                    source,
+                })?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(source) => {
+                return Err(CacheError::Io {
+                    path: path.to_string_lossy().to_string(),
+                    source,
+                })
+            }
+        };
+
+        Ok(Self { path, entries })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, input: P) -> Option<&CacheEntry> {
+        self.entries.get(&cache_key(input.as_ref()))
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &CacheEntry)> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (path.as_str(), entry))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entry for `input` if compressing it again with `options` would be redundant.
+    ///
+    /// `input_hash` is the current hash of the file, which matches the recorded output
+    /// when the file was overwritten in place, or the recorded input when the output went elsewhere.
+    pub fn lookup<P: AsRef<Path>>(
+        &self,
+        input: P,
+        input_hash: &str,
+        options: &CompressOptions,
+    ) -> Option<&CacheEntry> {
+        let entry = self.get(input)?;
+
+        if entry.options_hash != hash_options(options) {
+            return None;
+        }
+
+        // the first output replaced the input, so it was just hashed by the caller
+        let in_place = entry.output_hash == input_hash;
+        if !in_place && entry.input_hash != input_hash {
+            return None;
+        }
+
+        let outputs_unchanged = entry
+            .hashes()
+            .into_iter()
+            .filter(|(path, _)| !(in_place && *path == entry.output_path))
+            .all(|(path, recorded)| {
+                hash_file(path)
+                    .map(|hash| hash == recorded)
+                    .unwrap_or(false)
+            });
+
+        outputs_unchanged.then_some(entry)
+    }
+
+    /// Remember a successful compression. `input_hash` must be taken before compressing,
+    /// since the input may be overwritten.
+    pub fn record<P: AsRef<Path>>(
+        &mut self,
+        input: P,
+        input_hash: String,
+        options: &CompressOptions,
+        output: &Result,
+    ) -> result::Result<(), CacheError> {
+        let hash = |path: &str| {
+            hash_file(path).map_err(|source| CacheError::Io {
+                path: path.to_string(),
+                source,
+            })
+        };
+        let output_hash = hash(&output.path)?;
+        let mut output_hashes = BTreeMap::new();
+        for path in output.outputs.iter().map(|output| &output.path) {
+            if !output_hashes.contains_key(path) {
+                output_hashes.insert(path.clone(), hash(path)?);
+            }
+        }
+        output_hashes.insert(output.path.clone(), output_hash.clone());
+
+        self.entries.insert(
+            cache_key(input.as_ref()),
+            CacheEntry {
+                input_hash,
+                options_hash: hash_options(options),
+                output_path: output.path.clone(),
+                output_hash,
+                output_size: output.size,
+                extension: output.extension.clone(),
+                outputs: output.outputs.clone(),
+                output_hashes,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn remove<P: AsRef<Path>>(&mut self, input: P) -> Option<CacheEntry> {
+        self.entries.remove(&cache_key(input.as_ref()))
+    }
+
+    pub fn save(&self) -> result::Result<(), CacheError> {
+        let io_error = |source| CacheError::Io {
+            path: self.path.to_string_lossy().to_string(),
+            source,
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let contents =
+            serde_json::to_vec_pretty(&self.entries).map_err(|source| CacheError::Parse {
+                path: self.path.to_string_lossy().to_string(),
+                source,
+            })?;
+        fs::write(&self.path, contents).map_err(io_error)
+    }
+}
+
+impl Result {
+    /// Result reported for a file skipped because of a cache hit.
+    pub fn from_cache(entry: &CacheEntry) -> Self {
+        Result {
+            size: entry.output_size,
+            path: entry.output_path.clone(),
+            elapsed: 0,
+            extension: entry.extension.clone(),
+            warnings: Vec::new(),
+            cached: true,
+            outputs: entry.outputs.clone(),
+            selection: None,
+        }
+    }
+}
+
+fn cache_key(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let contents = fs::read(path)?;
+    Ok(blake3::hash(&contents).to_hex().to_string())
+}
+
+/// Hash of `options`, including the contents of an image watermark so replacing the logo misses the cache.
+pub fn hash_options(options: &CompressOptions) -> String {
+    let mut hasher = blake3::Hasher::new();
+    // serializing plain data can't fail
+    hasher.update(&serde_json::to_vec(options).unwrap_or_default());
+    if let Some(WatermarkSource::Image(path)) = options.watermark.as_ref().map(|w| &w.source) {
+        // an unreadable logo fails the compression anyway
+        if let Ok(contents) = fs::read(path) {
+            hasher.update(blake3::hash(&contents).as_bytes());
+        }
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_after_in_place_compression() {
+        let root = std::env::temp_dir().join("ashuk-cache-lookup");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("a.png");
+        fs::write(&input, b"original").unwrap();
+
+        let options = CompressOptions {
+            extension: "png".to_string(),
+            quality: Some(6.0),
+            ..Default::default()
+        };
+        let input_hash = hash_file(&input).unwrap();
+        // simulate an in-place compression
+        fs::write(&input, b"compressed").unwrap();
+        let output = Result {
+            size: 10,
+            path: input.to_string_lossy().to_string(),
+            elapsed: 1,
+            extension: "png".to_string(),
+            warnings: Vec::new(),
+            cached: false,
+            outputs: Vec::new(),
+            selection: None,
+        };
+
+        let mut cache = Cache::open(&CacheLocation::Directory(root.clone())).unwrap();
+        cache.record(&input, input_hash, &options, &output).unwrap();
+        cache.save().unwrap();
+
+        let cache = Cache::open(&CacheLocation::Directory(root.clone())).unwrap();
+        let current_hash = hash_file(&input).unwrap();
+        assert!(cache.lookup(&input, &current_hash, &options).is_some());
+
+        let other_options = CompressOptions {
+            quality: Some(2.0),
+            ..options.clone()
+        };
+        assert!(cache
+            .lookup(&input, &current_hash, &other_options)
+            .is_none());
+
+        fs::write(&input, b"edited").unwrap();
+        let edited_hash = hash_file(&input).unwrap();
+        assert!(cache.lookup(&input, &edited_hash, &options).is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_checks_every_output() {
+        let root = std::env::temp_dir().join("ashuk-cache-outputs");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let input = root.join("a.png");
+        let logo = root.join("logo.png");
+        fs::write(&input, b"original").unwrap();
+        fs::write(&logo, b"logo").unwrap();
+        let outputs: Vec<Output> = ["a-320w.webp", "a-640w.webp"]
+            .iter()
+            .map(|name| {
+                let path = root.join(name);
+                fs::write(&path, name.as_bytes()).unwrap();
+                Output {
+                    path: path.to_string_lossy().to_string(),
+                    size: 8,
+                    extension: "webp".to_string(),
+                    format: crate::format_meta::ImageFormat::WebP,
+                    width: 320,
+                    height: 200,
+                }
+            })
+            .collect();
+
+        let options = CompressOptions {
+            extension: "webp".to_string(),
+            watermark: Some(crate::watermark::Watermark::new(WatermarkSource::Image(
+                logo.clone(),
+            ))),
+            ..Default::default()
+        };
+        let output = Result {
+            size: 8,
+            path: outputs[0].path.clone(),
+            elapsed: 1,
+            extension: "webp".to_string(),
+            warnings: Vec::new(),
+            cached: false,
+            outputs: outputs.clone(),
+            selection: None,
+        };
+        let input_hash = hash_file(&input).unwrap();
+        let mut cache = Cache::open(&CacheLocation::Directory(root.clone())).unwrap();
+        cache
+            .record(&input, input_hash.clone(), &options, &output)
+            .unwrap();
+        assert!(cache.lookup(&input, &input_hash, &options).is_some());
+
+        // the second variant was deleted, the first one alone isn't enough
+        fs::remove_file(&outputs[1].path).unwrap();
+        assert!(cache.lookup(&input, &input_hash, &options).is_none());
+        fs::write(&outputs[1].path, b"a-640w.webp").unwrap();
+        assert!(cache.lookup(&input, &input_hash, &options).is_some());
+
+        // same options, different logo
+        fs::write(&logo, b"new logo").unwrap();
+        assert!(cache.lookup(&input, &input_hash, &options).is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
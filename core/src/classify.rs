@@ -0,0 +1,188 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::result;
+
+use crate::compresser::{self, CompressError};
+use crate::format_meta::FormatDetection;
+use crate::limits::Limits;
+
+// statistics are taken from a copy at most this large, sampled without blending colours
+const SAMPLE_SIZE: u32 = 512;
+// counting stops here, photos easily have far more
+const MAX_COLORS: usize = 4096;
+// luma difference to a neighbour that counts as a hard edge
+const EDGE_THRESHOLD: i32 = 96;
+
+/// What an image mostly shows, which decides the format that suits it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    /// smooth gradients and noise, lossy formats work best
+    Photo,
+    /// flat colours such as logos and illustrations, lossless formats work best
+    Graphic,
+    /// many hard edges on flat colour such as screenshots of text, lossy artifacts show badly
+    Text,
+}
+
+impl fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Classification {
+    pub kind: ContentKind,
+    /// some pixel is not fully opaque, unlike an alpha channel that is only declared
+    pub has_alpha: bool,
+    /// distinct colours in the sample, capped at 4096
+    pub colors: u32,
+    /// share of neighbouring pixels with exactly the same colour
+    pub flat_ratio: f64,
+    /// share of pixels on a hard edge
+    pub edge_density: f64,
+}
+
+/// Classify `image` from cheap statistics of a downscaled copy.
+pub fn classify_image(image: &DynamicImage) -> Classification {
+    let (width, height) = image.dimensions();
+    let sample = if width > SAMPLE_SIZE || height > SAMPLE_SIZE {
+        // nearest keeps the original colours and edges, a smoothing filter would invent new ones
+        image.resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Nearest)
+    } else {
+        image.clone()
+    };
+
+    let has_alpha = image.color().has_alpha() && image.pixels().any(|(_, _, pixel)| pixel[3] < 255);
+
+    let rgb = sample.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    let luma = |x: u32, y: u32| {
+        let [r, g, b] = rgb.get_pixel(x, y).0;
+        (299 * r as i32 + 587 * g as i32 + 114 * b as i32) / 1000
+    };
+
+    let mut colors = HashSet::new();
+    let (mut flat, mut pairs, mut edges) = (0u64, 0u64, 0u64);
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = rgb.get_pixel(x, y);
+            if colors.len() < MAX_COLORS {
+                colors.insert(pixel.0);
+            }
+
+            let mut edge = false;
+            if x + 1 < width {
+                pairs += 1;
+                flat += (rgb.get_pixel(x + 1, y) == pixel) as u64;
+                edge |= (luma(x + 1, y) - luma(x, y)).abs() >= EDGE_THRESHOLD;
+            }
+            if y + 1 < height {
+                edge |= (luma(x, y + 1) - luma(x, y)).abs() >= EDGE_THRESHOLD;
+            }
+            edges += edge as u64;
+        }
+    }
+
+    let flat_ratio = flat as f64 / pairs.max(1) as f64;
+    let edge_density = edges as f64 / (width as u64 * height as u64).max(1) as f64;
+    let kind = if colors.len() > MAX_COLORS / 2 && flat_ratio < 0.5 {
+        ContentKind::Photo
+    } else if edge_density > 0.08 {
+        ContentKind::Text
+    } else {
+        ContentKind::Graphic
+    };
+
+    Classification {
+        kind,
+        has_alpha,
+        colors: colors.len() as u32,
+        flat_ratio,
+        edge_density,
+    }
+}
+
+/// Decode `file_path` and classify it. The file is only read.
+pub fn classify(
+    file_path: &str,
+    detection: FormatDetection,
+    limits: &Limits,
+) -> result::Result<Classification, CompressError> {
+    let detected = compresser::detect(file_path, detection)?;
+    let image = compresser::decode(file_path, detected.format, limits)?;
+
+    Ok(classify_image(&image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watermark::render_text;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    #[test]
+    fn test_classify_image() {
+        let photo = RgbImage::from_fn(300, 200, |x, y| {
+            // smooth gradient with sensor-like noise
+            let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) % 13;
+            Rgb([
+                (x / 2 + noise) as u8,
+                (y + noise) as u8,
+                ((x + y) / 3) as u8,
+            ])
+        });
+        let photo = classify_image(&DynamicImage::ImageRgb8(photo));
+        assert_eq!(photo.kind, ContentKind::Photo);
+        assert!(!photo.has_alpha);
+
+        let logo = RgbaImage::from_fn(300, 200, |x, y| {
+            let (dx, dy) = (x as i32 - 150, y as i32 - 100);
+            match dx * dx + dy * dy {
+                d if d < 40 * 40 => Rgba([230, 60, 30, 255]),
+                d if d < 80 * 80 => Rgba([30, 60, 230, 255]),
+                _ => Rgba([0, 0, 0, 0]),
+            }
+        });
+        let logo = classify_image(&DynamicImage::ImageRgba8(logo));
+        assert_eq!(logo.kind, ContentKind::Graphic);
+        assert!(logo.has_alpha);
+
+        let mut page = RgbImage::from_pixel(300, 200, Rgb([255, 255, 255]));
+        let line = render_text("The quick brown fox jumps over", [0, 0, 0]);
+        for row in 0..16 {
+            for (x, y, pixel) in line.enumerate_pixels() {
+                if pixel[3] > 0 && x + 4 < 300 {
+                    page.put_pixel(x + 4, row * 12 + y + 4, Rgb([0, 0, 0]));
+                }
+            }
+        }
+        assert_eq!(
+            classify_image(&DynamicImage::ImageRgb8(page)).kind,
+            ContentKind::Text
+        );
+    }
+
+    #[test]
+    fn test_classify_within_limits() {
+        let path = std::env::temp_dir().join("ashuk-classify-limits.png");
+        RgbImage::new(64, 32).save(&path).unwrap();
+        let path = path.to_string_lossy();
+
+        let limits = Limits {
+            max_width: Some(48),
+            ..Limits::default()
+        };
+        assert!(matches!(
+            classify(&path, FormatDetection::Content, &limits),
+            Err(CompressError::LimitExceeded { actual: 64, .. })
+        ));
+        assert!(classify(&path, FormatDetection::Content, &Limits::default()).is_ok());
+
+        std::fs::remove_file(path.as_ref()).unwrap();
+    }
+}
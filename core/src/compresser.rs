@@ -1,51 +1,289 @@
+use image::codecs::gif::GifDecoder;
 use image::io::Reader as ImageReader;
-use image::ImageError;
-
-use mozjpeg::{ColorSpace, Compress, ScanMode};
-use oxipng;
-use webp;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageError};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use std::borrow;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Cursor};
 use std::path;
 use std::result;
 use std::time::Instant;
 
-use crate::format_meta::ImageFormat;
+use crate::auto::{self, AutoOptions, Selection, AUTO_EXTENSION};
+use crate::encoder::{self, EncoderSettings};
+use crate::format_meta::{DetectedFormat, FormatDetection, ImageFormat, ImageFormatError};
+use crate::jpeg_quality::{self, SourceQuality};
+use crate::jpegtran::{self, JpegTransformOptions, TransformError};
+use crate::limits::{Header, Limit, Limits};
+use crate::rules::{self, ConversionRule};
+use crate::transform::{self, Crop, Resize};
+use crate::watermark::{Overlay, Watermark};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Read,
+    Decode,
+    Encode,
+    Write,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            Stage::Read => "read",
+            Stage::Decode => "decode",
+            Stage::Encode => "encode",
+            Stage::Write => "write",
+        };
+        write!(f, "{}", stage)
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum CompressError {
     #[error("This operation from {0} to {1} is not supported")]
     Unsupported(ImageFormat, ImageFormat),
-    #[error(transparent)]
-    PngError(#[from] oxipng::PngError),
-    #[error("file io error: {0}")]
-    Disconnect(#[from] io::Error),
-    #[error("unknown error: {0}")]
-    Unknown(#[from] ImageError),
+    #[error("{path} is not a supported image file")]
+    UnsupportedInput { path: String },
+    #[error("{0} is not a supported output extension")]
+    UnsupportedExtension(String),
+    #[error("{path} has no parent directory or file name")]
+    InvalidPath { path: String },
+    #[error("{path} is both the original and the compressed image")]
+    SameFile { path: String },
+    #[error("{path} can't be estimated with {option}, only a real run shows their size")]
+    Unestimable { path: String, option: &'static str },
+    #[error("failed to {stage} {path}: {source}")]
+    Io {
+        path: String,
+        stage: Stage,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to decode {path}: {source}")]
+    Decode {
+        path: String,
+        #[source]
+        source: ImageError,
+    },
+    #[error("{codec} codec failed to {stage} {path}: {reason}")]
+    Codec {
+        path: String,
+        stage: Stage,
+        codec: ImageFormat,
+        reason: String,
+    },
+    #[error("{path} is {width}x{height}, {codec} can't encode more than {max} pixels on a side")]
+    Dimensions {
+        path: String,
+        codec: ImageFormat,
+        width: u32,
+        height: u32,
+        max: u32,
+    },
+    #[error("{path} is over the {limit} limit: {actual} > {max}")]
+    LimitExceeded {
+        path: String,
+        limit: Limit,
+        actual: u64,
+        max: u64,
+    },
+    #[error("failed to transform {path}: {source}")]
+    Transform {
+        path: String,
+        #[source]
+        source: TransformError,
+    },
 }
 
-fn set_file_to_same_dir(file_path: &str, extension: &str) -> String {
+impl CompressError {
+    pub(crate) fn io(path: &str, stage: Stage) -> impl FnOnce(io::Error) -> CompressError {
+        let path = path.to_string();
+        move |source| CompressError::Io {
+            path,
+            stage,
+            source,
+        }
+    }
+
+    pub(crate) fn codec(path: &str, codec: ImageFormat) -> impl FnOnce(String) -> CompressError {
+        let path = path.to_string();
+        move |reason| CompressError::Codec {
+            path,
+            stage: Stage::Encode,
+            codec,
+            reason,
+        }
+    }
+
+    /// `Unsupported` when the input or the requested output isn't an image ashuk handles, `Failed` otherwise.
+    pub fn status(&self) -> Status {
+        match self {
+            CompressError::Unsupported(..)
+            | CompressError::UnsupportedInput { .. }
+            | CompressError::UnsupportedExtension(..) => Status::Unsupported,
+            _ => Status::Failed,
+        }
+    }
+}
+
+fn set_file_to_same_dir(
+    file_path: &str,
+    extension: &str,
+    suffix: &str,
+) -> result::Result<String, CompressError> {
     let path = path::Path::new(file_path);
 
-    let ext = ".".to_string() + extension;
+    let invalid_path = || CompressError::InvalidPath {
+        path: file_path.to_string(),
+    };
+    let parent = path.parent().ok_or_else(invalid_path)?;
+    let file_stem = path.file_stem().ok_or_else(invalid_path)?;
 
-    let output_file_path =
-        // dirname
-        path.parent().unwrap().to_string_lossy()
-        // add separator
-        + borrow::Cow::from(path::MAIN_SEPARATOR.to_string())
-        // add filename without extension
-        + path.file_stem().unwrap().to_string_lossy()
-        // add extension
-        + path::Path::new(&ext).to_string_lossy();
+    let mut file_name = file_stem.to_os_string();
+    file_name.push(suffix);
+    file_name.push(".");
+    file_name.push(extension);
+
+    // joining keeps bare file names relative instead of prefixing them with a separator
+    Ok(parent.join(file_name).to_string_lossy().to_string())
+}
+
+fn set_file_to_dir(
+    file_path: &str,
+    extension: &str,
+    suffix: &str,
+    dir: &path::Path,
+) -> result::Result<String, CompressError> {
+    let file_stem =
+        path::Path::new(file_path)
+            .file_stem()
+            .ok_or_else(|| CompressError::InvalidPath {
+                path: file_path.to_string(),
+            })?;
+
+    let mut file_name = file_stem.to_os_string();
+    file_name.push(suffix);
+    file_name.push(".");
+    file_name.push(extension);
 
-    output_file_path.to_string()
+    Ok(dir.join(file_name).to_string_lossy().to_string())
+}
+
+// path of an output, see `OutputLocation`
+fn output_file_path(
+    file_path: &str,
+    extension: &str,
+    suffix: &str,
+    output: &OutputLocation,
+) -> result::Result<String, CompressError> {
+    match output {
+        OutputLocation::SameDir => set_file_to_same_dir(file_path, extension, suffix),
+        OutputLocation::Dir(dir) => set_file_to_dir(file_path, extension, suffix, dir),
+    }
+}
+
+// extension of an output of `format`, outputs in the input's format keep the input's, e.g. `.jpeg`
+fn confirmed_extension(
+    file_path: &str,
+    input: ImageFormat,
+    format: ImageFormat,
+    extension: &str,
+) -> result::Result<String, CompressError> {
+    if input != format {
+        return Ok(extension.to_string());
+    }
+    path::Path::new(file_path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_string)
+        .ok_or_else(|| CompressError::InvalidPath {
+            path: file_path.to_string(),
+        })
+}
+
+// write an output, creating its directory when missing
+fn write_output(path: &str, contents: &[u8]) -> result::Result<(), CompressError> {
+    if let Some(dir) = path::Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(CompressError::io(&dir.to_string_lossy(), Stage::Write))?;
+    }
+    fs::write(path, contents).map_err(CompressError::io(path, Stage::Write))
+}
+
+/// Every path `compress_to_target_extension` may write for `file_path`, without encoding anything.
+///
+/// Auto targets give one path per candidate format and variants one per configured width and format.
+pub fn output_paths(
+    file_path: &str,
+    options: &CompressOptions,
+) -> result::Result<Vec<String>, CompressError> {
+    let options = rules::route(options, path::Path::new(file_path));
+    let input = detect(file_path, options.detection)?.format;
+    let extension = options.target_extension(input);
+    let suffix = options.suffix.as_deref().unwrap_or("");
+    let path = |extension: &str, suffix: &str| {
+        output_file_path(file_path, extension, suffix, &options.output)
+    };
+
+    if let Some(variants) = &options.variants {
+        let extensions = if variants.formats.is_empty() {
+            vec![extension]
+        } else {
+            variants.formats.clone()
+        };
+        return variants
+            .widths
+            .iter()
+            .flat_map(|width| {
+                let suffix = format!("{}-{}w", suffix, width);
+                extensions
+                    .iter()
+                    .map(|extension| path(extension, &suffix))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+    }
+
+    let formats = if extension != AUTO_EXTENSION {
+        let format = ImageFormat::from_extension(&extension)
+            .ok_or_else(|| CompressError::UnsupportedExtension(extension.clone()))?;
+        vec![(format, extension)]
+    } else if input == ImageFormat::Gif {
+        vec![(
+            ImageFormat::WebP,
+            ImageFormat::WebP.get_representative_ext_str(),
+        )]
+    } else {
+        options
+            .auto
+            .candidates(input)?
+            .into_iter()
+            .map(|format| (format, format.get_representative_ext_str()))
+            .collect()
+    };
+    formats
+        .into_iter()
+        .map(|(format, extension)| {
+            path(
+                &confirmed_extension(file_path, input, format, &extension)?,
+                suffix,
+            )
+        })
+        .collect()
+}
+
+/// Where compressed files are written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub enum OutputLocation {
+    /// next to the input, overwriting it when the format doesn't change
+    #[default]
+    SameDir,
+    /// inside the given directory, which is created when missing
+    Dir(path::PathBuf),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -57,18 +295,162 @@ pub enum Status {
     Unsupported,
 }
 
+/// One file written for an input.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Output {
+    pub path: String,
+    pub size: u64,
+    pub extension: String,
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Outcome for one input. `size`, `path` and `extension` describe the first of `outputs`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Result {
     pub size: u64,
     pub path: String,
     pub elapsed: u64,
     pub extension: String,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// skipped because an identical compression was already recorded
+    #[serde(default)]
+    pub cached: bool,
+    /// every file written for the input, more than one for responsive variants
+    #[serde(default)]
+    pub outputs: Vec<Output>,
+    /// candidates tried for an auto target and why the output format won
+    #[serde(default)]
+    pub selection: Option<Selection>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Responsive image set: every width in every format, encoded from a single decode.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Variants {
+    /// target widths, wider than the source are skipped; the source width when empty
+    #[serde(default)]
+    pub widths: Vec<u32>,
+    /// target extensions; `CompressOptions::extension` when empty
+    #[serde(default)]
+    pub formats: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CompressOptions {
     pub quality: Option<f32>,
+    /// target extension, every file keeps its own format when empty
     pub extension: String,
+    #[serde(default)]
+    pub detection: FormatDetection,
+    #[serde(default)]
+    pub output: OutputLocation,
+    /// appended to the file name, before the extension
+    #[serde(default)]
+    pub suffix: Option<String>,
+    #[serde(default)]
+    pub encoder: EncoderSettings,
+    /// applied before `resize`
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    #[serde(default)]
+    pub resize: Option<Resize>,
+    /// composited after cropping and resizing
+    #[serde(default)]
+    pub watermark: Option<Watermark>,
+    /// per-source-format routing, the first matching rule replaces `extension` and `quality`
+    #[serde(default)]
+    pub rules: Vec<ConversionRule>,
+    /// write a set of sizes and formats instead of a single output
+    #[serde(default)]
+    pub variants: Option<Variants>,
+    /// how the format is chosen when `extension` is `auto`
+    #[serde(default)]
+    pub auto: AutoOptions,
+    /// jpg to jpg encodes above the estimated quality of the source
+    #[serde(default)]
+    pub source_quality: SourceQuality,
+    /// inputs over these are rejected before decoding
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+impl CompressOptions {
+    /// Extension a `source` file is encoded to, its own format when `extension` is empty.
+    pub fn target_extension(&self, source: ImageFormat) -> String {
+        if self.extension.is_empty() {
+            source.get_representative_ext_str()
+        } else {
+            self.extension.clone()
+        }
+    }
+}
+
+pub(crate) fn detect(
+    file_path: &str,
+    detection: FormatDetection,
+) -> result::Result<DetectedFormat, CompressError> {
+    ImageFormat::detect(file_path, detection).map_err(|err| match err {
+        ImageFormatError::Io(source) => CompressError::Io {
+            path: file_path.to_string(),
+            stage: Stage::Read,
+            source,
+        },
+        ImageFormatError::Unsupported => CompressError::UnsupportedInput {
+            path: file_path.to_string(),
+        },
+    })
+}
+
+/// Decode `file_path`, after checking its header against `limits`.
+pub(crate) fn decode(
+    file_path: &str,
+    format: ImageFormat,
+    limits: &Limits,
+) -> result::Result<DynamicImage, CompressError> {
+    let contents = fs::read(file_path).map_err(CompressError::io(file_path, Stage::Read))?;
+    limits.check_contents(file_path, &contents, format)?;
+
+    // decode by the detected format, not the one implied by the extension
+    let mut reader = ImageReader::with_format(Cursor::new(contents), format.to_image_format());
+    reader.limits(limits.image_limits());
+    reader.decode().map_err(|source| CompressError::Decode {
+        path: file_path.to_string(),
+        source,
+    })
+}
+
+fn decode_frames(file_path: &str, limits: &Limits) -> result::Result<Vec<Frame>, CompressError> {
+    let decode_error = |source| CompressError::Decode {
+        path: file_path.to_string(),
+        source,
+    };
+
+    let contents = fs::read(file_path).map_err(CompressError::io(file_path, Stage::Read))?;
+    limits.check_contents(file_path, &contents, ImageFormat::Gif)?;
+    let mut decoder = GifDecoder::new(Cursor::new(contents)).map_err(decode_error)?;
+    decoder
+        .set_limits(limits.image_limits())
+        .map_err(decode_error)?;
+
+    // the header count stops at a truncated block, so frames are counted again as they decode
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        let frame = frame.map_err(decode_error)?;
+        let (width, height) = frame.buffer().dimensions();
+        limits.check(
+            file_path,
+            &Header {
+                width,
+                height,
+                bytes_per_pixel: 4,
+                frames: frames.len() as u32 + 1,
+            },
+        )?;
+        frames.push(frame);
+    }
+    Ok(frames)
 }
 
 pub fn compress_to_target_extension(
@@ -76,113 +458,532 @@ pub fn compress_to_target_extension(
     options: CompressOptions,
 ) -> result::Result<Result, CompressError> {
     let start = Instant::now();
+    // a no-op for jobs the scheduler already routed, their rules are cleared
+    let mut options = rules::route(&options, path::Path::new(file_path));
+
+    let detected = detect(file_path, options.detection)?;
+    let input_extension = detected.format;
+    options.extension = options.target_extension(input_extension);
+
+    let mut warnings = Vec::new();
+    if let Some(extension_format) = detected
+        .extension_format
+        .filter(|_| detected.is_mismatched())
+    {
+        warnings.push(format!(
+            "{} has a {} extension but contains {} data",
+            file_path, extension_format, input_extension
+        ));
+    }
 
-    let options = options.clone();
+    if let Some(variants) = &options.variants {
+        let outputs = compress_variants(
+            file_path,
+            input_extension,
+            &options,
+            variants,
+            &mut warnings,
+        )?;
+        return Ok(Result::from_outputs(
+            outputs,
+            start.elapsed().as_millis() as u64,
+            warnings,
+        ));
+    }
 
-    let input_extension = ImageFormat::from_path(&file_path).unwrap();
+    if options.extension == AUTO_EXTENSION {
+        return compress_auto(file_path, input_extension, options, start, warnings);
+    }
 
-    let output_extension = ImageFormat::from_extension(&options.clone().extension).unwrap();
+    let output_extension = ImageFormat::from_extension(&options.extension)
+        .ok_or_else(|| CompressError::UnsupportedExtension(options.extension.clone()))?;
 
-    if let false = input_extension.can_compress(&output_extension) {
+    if !input_extension.can_compress(&output_extension) {
         return Err(CompressError::Unsupported(
             input_extension,
             output_extension,
         ));
     };
 
-    let confirmed_extension = if input_extension == output_extension {
-        // overwrite
-        path::Path::new(file_path)
-            .extension()
-            .and_then(OsStr::to_str)
-            .unwrap()
-    } else {
-        // alter extension
-        &options.extension
+    let confirmed_extension = confirmed_extension(
+        file_path,
+        input_extension,
+        output_extension,
+        &options.extension,
+    )?;
+    let suffix = options.suffix.as_deref().unwrap_or("");
+    let output_file_path =
+        output_file_path(file_path, &confirmed_extension, suffix, &options.output)?;
+
+    let encoded = encode_file(
+        file_path,
+        input_extension,
+        output_extension,
+        &mut options,
+        None,
+        &mut warnings,
+    )?;
+    // a kept source is already in place when it would overwrite itself
+    if !(encoded.kept && output_file_path == file_path) {
+        write_output(&output_file_path, &encoded.bytes)?;
+    }
+    let (width, height) = (encoded.width, encoded.height);
+
+    let end = start.elapsed();
+
+    let output = Output {
+        size: encoded.bytes.len() as u64,
+        path: output_file_path,
+        extension: confirmed_extension,
+        format: output_extension,
+        width,
+        height,
     };
+    Ok(Result::from_outputs(
+        vec![output],
+        end.as_millis() as u64,
+        warnings,
+    ))
+}
 
-    let result = match output_extension {
-        ImageFormat::WebP => {
-            let output_file_path = set_file_to_same_dir(&file_path, confirmed_extension);
+/// Lossless jpeg and `SourceQuality` decisions for one output, returned as `(lossless_transcode, keep_source)`.
+///
+/// The jpeg quality may be lowered to the source's in `options`.
+fn jpeg_policies(
+    file_path: &str,
+    input_extension: ImageFormat,
+    output_extension: ImageFormat,
+    options: &mut CompressOptions,
+    changes_pixels: bool,
+    warnings: &mut Vec<String>,
+) -> (bool, bool) {
+    let lossless_jpeg = output_extension == ImageFormat::Jpeg && options.encoder.jpeg.lossless;
+    if lossless_jpeg && (input_extension != ImageFormat::Jpeg || changes_pixels) {
+        warnings.push(format!(
+            "{} was re-encoded, lossless jpeg only applies to jpg sources without crop, resize or watermark",
+            file_path
+        ));
+    }
 
-            let decoded = ImageReader::open(file_path)?.decode()?;
+    let lossless_transcode =
+        lossless_jpeg && input_extension == ImageFormat::Jpeg && !changes_pixels;
 
-            let encoder = webp::Encoder::from_image(&decoded).unwrap();
+    let mut keep_source = false;
+    if input_extension == ImageFormat::Jpeg
+        && output_extension == ImageFormat::Jpeg
+        && !lossless_transcode
+        && options.source_quality != SourceQuality::Ignore
+    {
+        let requested = options.quality_for(ImageFormat::Jpeg);
+        if let Some(source) =
+            jpeg_quality::estimate_file(file_path).filter(|source| requested > *source)
+        {
+            if options.source_quality == SourceQuality::Skip && !changes_pixels {
+                keep_source = true;
+                warnings.push(format!(
+                    "{} was kept as is, it was saved at about quality {} and quality {} would only make it larger",
+                    file_path, source, requested
+                ));
+            } else {
+                options.quality = Some(source);
+                warnings.push(format!(
+                    "{} was encoded at quality {} instead of {}, the quality it was saved at",
+                    file_path, source, requested
+                ));
+            }
+        }
+    }
 
-            let contents = encoder.encode(options.clone().quality.unwrap_or(75.0));
+    (lossless_transcode, keep_source)
+}
 
-            fs::write(&output_file_path, &*contents)?;
+/// A single output in memory, exactly as `compress_to_target_extension` writes it.
+pub(crate) struct Encoded {
+    pub bytes: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// the input was kept as is, see `SourceQuality::Skip`
+    pub kept: bool,
+}
 
-            let end = start.elapsed();
+/// Encode `file_path` to `output_extension` the way a single output is written, without writing it.
+///
+/// `prepared` is the decoded and transformed input when the caller already has one.
+/// The jpeg quality may be lowered to the source's, `options` is left with the one used.
+pub(crate) fn encode_file(
+    file_path: &str,
+    input_extension: ImageFormat,
+    output_extension: ImageFormat,
+    options: &mut CompressOptions,
+    prepared: Option<&DynamicImage>,
+    warnings: &mut Vec<String>,
+) -> result::Result<Encoded, CompressError> {
+    let changes_pixels = transform::changes_pixels(options);
+    let (lossless_transcode, keep_source) = jpeg_policies(
+        file_path,
+        input_extension,
+        output_extension,
+        options,
+        changes_pixels,
+        warnings,
+    );
 
-            Result {
-                size: fs::metadata(&output_file_path)?.len(),
-                path: output_file_path.clone(),
-                elapsed: end.as_millis() as u64,
-                extension: confirmed_extension.to_string(),
-            }
+    let read = || fs::read(file_path).map_err(CompressError::io(file_path, Stage::Read));
+    let encoded = |bytes: Vec<u8>| -> result::Result<Encoded, CompressError> {
+        let (width, height) =
+            ImageReader::with_format(Cursor::new(&bytes), output_extension.to_image_format())
+                .into_dimensions()
+                .map_err(|source| CompressError::Decode {
+                    path: file_path.to_string(),
+                    source,
+                })?;
+        Ok(Encoded {
+            bytes,
+            width,
+            height,
+            kept: keep_source,
+        })
+    };
+
+    match output_extension {
+        ImageFormat::Jpeg if keep_source => encoded(read()?),
+        ImageFormat::Jpeg if lossless_transcode => {
+            let contents = read()?;
+            options
+                .limits
+                .check_contents(file_path, &contents, ImageFormat::Jpeg)?;
+            let optimized = jpegtran::optimize(
+                &contents,
+                options.encoder.jpeg.progressive,
+                options.encoder.jpeg.markers,
+            )
+            .map_err(CompressError::codec(file_path, ImageFormat::Jpeg))?;
+            encoded(optimized)
         }
-        ImageFormat::Jpeg => {
-            let output_file_path = set_file_to_same_dir(&file_path, confirmed_extension);
+        ImageFormat::Png if !transform::changes_pixels(options) => {
+            let contents = read()?;
+            // oxipng decodes on its own, so only the header is checked
+            options
+                .limits
+                .check_contents(file_path, &contents, ImageFormat::Png)?;
 
-            let decoded = ImageReader::open(file_path)?.decode()?;
+            // don't use multi process outside this function, because of oxipng process image with multithreading
+            let optimized = oxipng::optimize_from_memory(
+                &contents,
+                &oxipng::Options::from_preset(options.quality_for(ImageFormat::Png) as u8),
+            )
+            .map_err(|err| CompressError::codec(file_path, ImageFormat::Png)(err.to_string()))?;
+            encoded(optimized)
+        }
+        ImageFormat::WebP if input_extension == ImageFormat::Gif => {
+            // every frame is kept, so animations stay animated
+            let overlay = load_overlay(options)?;
+            let frames = decode_frames(file_path, &options.limits)?
+                .into_iter()
+                .map(|frame| transform::apply_frame(frame, options))
+                .map(|frame| match &overlay {
+                    Some(overlay) => overlay.apply_frame(frame),
+                    None => frame,
+                })
+                .collect::<Vec<Frame>>();
+            let (width, height) = frames
+                .first()
+                .map_or((0, 0), |frame| frame.buffer().dimensions());
 
-            let mut comp = Compress::new(ColorSpace::JCS_RGB);
-            let width = decoded.width() as usize;
-            let height = decoded.height() as usize;
-            comp.set_scan_optimization_mode(ScanMode::AllComponentsTogether);
-            comp.set_quality(options.clone().quality.unwrap_or(75.0));
+            let bytes = encoder::encode_webp_animation(
+                &frames,
+                options.quality_for(ImageFormat::WebP),
+                options.encoder.webp.lossless,
+            )
+            .map_err(CompressError::codec(file_path, ImageFormat::WebP))?;
+            Ok(Encoded {
+                bytes,
+                width,
+                height,
+                kept: false,
+            })
+        }
+        _ => {
+            let decoded;
+            let transformed = match prepared {
+                Some(prepared) => prepared,
+                None => {
+                    decoded = prepare(
+                        decode(file_path, input_extension, &options.limits)?,
+                        options,
+                    )?;
+                    &decoded
+                }
+            };
+            let bytes = encoder::encode(transformed, output_extension, options, file_path)?;
+            Ok(Encoded {
+                bytes,
+                width: transformed.width(),
+                height: transformed.height(),
+                kept: false,
+            })
+        }
+    }
+}
 
-            comp.set_size(width, height);
+/// Write whichever candidate format is smallest at the target SSIM.
+fn compress_auto(
+    file_path: &str,
+    input_extension: ImageFormat,
+    options: CompressOptions,
+    start: Instant,
+    warnings: Vec<String>,
+) -> result::Result<Result, CompressError> {
+    if input_extension == ImageFormat::Gif {
+        // animated webp is the only target for gif
+        return compress_to_target_extension(
+            file_path,
+            CompressOptions {
+                extension: ImageFormat::WebP.get_representative_ext_str(),
+                ..options
+            },
+        );
+    }
 
-            comp.set_mem_dest();
-            comp.start_compress();
+    let decoded = decode(file_path, input_extension, &options.limits)?;
+    let transformed = prepare(decoded, &options)?;
+    let (selection, contents) = auto::select(&transformed, input_extension, &options)?;
 
-            let pixels = decoded.as_bytes();
-            assert!(comp.write_scanlines(pixels));
+    let format = selection.chosen.format;
+    let extension = confirmed_extension(
+        file_path,
+        input_extension,
+        format,
+        &format.get_representative_ext_str(),
+    )?;
+    let suffix = options.suffix.as_deref().unwrap_or("");
+    let output_file_path = output_file_path(file_path, &extension, suffix, &options.output)?;
 
-            comp.finish_compress();
-            let contents = comp.data_to_vec().unwrap();
+    write_output(&output_file_path, &contents)?;
 
-            fs::write(&output_file_path, contents)?;
+    let output = Output {
+        size: contents.len() as u64,
+        path: output_file_path,
+        extension,
+        format,
+        width: transformed.width(),
+        height: transformed.height(),
+    };
+    let mut result =
+        Result::from_outputs(vec![output], start.elapsed().as_millis() as u64, warnings);
+    result.selection = Some(selection);
+    Ok(result)
+}
 
-            let end = start.elapsed();
+/// Rotate or flip a jpg without re-encoding it, writing it where a compression would.
+pub fn transform_jpeg(
+    file_path: &str,
+    options: &JpegTransformOptions,
+) -> result::Result<Result, CompressError> {
+    let start = Instant::now();
 
-            Result {
-                size: fs::metadata(&output_file_path)?.len(),
-                path: output_file_path.clone(),
-                elapsed: end.as_millis() as u64,
-                extension: confirmed_extension.to_string(),
-            }
-        }
-        ImageFormat::Png => {
-            // don't use multi process outside this function, because of oxipng process image with multithreading
-            let output_file_path = set_file_to_same_dir(&file_path, confirmed_extension);
+    let detected = detect(file_path, options.detection)?;
+    if detected.format != ImageFormat::Jpeg {
+        return Err(CompressError::Unsupported(
+            detected.format,
+            ImageFormat::Jpeg,
+        ));
+    }
 
-            let input = path::PathBuf::from(&file_path);
-            let output = path::PathBuf::from(&output_file_path);
+    let extension = path::Path::new(file_path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| CompressError::InvalidPath {
+            path: file_path.to_string(),
+        })?;
+    let suffix = options.suffix.as_deref().unwrap_or("");
+    let output_file_path = output_file_path(file_path, extension, suffix, &options.output)?;
 
-            oxipng::optimize(
-                &oxipng::InFile::Path(input),
-                &oxipng::OutFile::Path(Some(output)),
-                &oxipng::Options::from_preset(options.clone().quality.unwrap_or(6.0) as u8),
-            )?;
+    let contents = fs::read(file_path).map_err(CompressError::io(file_path, Stage::Read))?;
+    options
+        .limits
+        .check_contents(file_path, &contents, ImageFormat::Jpeg)?;
+    let transformed =
+        jpegtran::transform(&contents, options).map_err(|source| CompressError::Transform {
+            path: file_path.to_string(),
+            source,
+        })?;
+    write_output(&output_file_path, &transformed)?;
 
-            let end = start.elapsed();
+    let (width, height) =
+        image::image_dimensions(&output_file_path).map_err(|source| CompressError::Decode {
+            path: output_file_path.clone(),
+            source,
+        })?;
+    let output = Output {
+        size: transformed.len() as u64,
+        path: output_file_path,
+        extension: extension.to_string(),
+        format: ImageFormat::Jpeg,
+        width,
+        height,
+    };
+    Ok(Result::from_outputs(
+        vec![output],
+        start.elapsed().as_millis() as u64,
+        Vec::new(),
+    ))
+}
 
-            Result {
-                size: fs::metadata(&output_file_path)?.len(),
-                path: output_file_path.clone(),
-                elapsed: end.as_millis() as u64,
-                extension: confirmed_extension.to_string(),
-            }
+/// Crop, resize and watermark a decoded image as `options` request.
+pub(crate) fn prepare(
+    image: DynamicImage,
+    options: &CompressOptions,
+) -> result::Result<DynamicImage, CompressError> {
+    Ok(composite(
+        transform::apply(image, options),
+        load_overlay(options)?.as_ref(),
+    ))
+}
+
+fn load_overlay(options: &CompressOptions) -> result::Result<Option<Overlay>, CompressError> {
+    options
+        .watermark
+        .as_ref()
+        .map(|watermark| watermark.load(&options.limits))
+        .transpose()
+}
+
+fn composite(image: DynamicImage, overlay: Option<&Overlay>) -> DynamicImage {
+    match overlay {
+        Some(overlay) => overlay.apply(image),
+        None => image,
+    }
+}
+
+impl Result {
+    /// Bytes written for the input, summed over every variant.
+    pub fn total_size(&self) -> u64 {
+        if self.outputs.is_empty() {
+            self.size
+        } else {
+            self.outputs.iter().map(|output| output.size).sum()
         }
-        _ => {
-            unimplemented!()
+    }
+
+    fn from_outputs(outputs: Vec<Output>, elapsed: u64, warnings: Vec<String>) -> Self {
+        // callers always write at least one output
+        let first = &outputs[0];
+        Result {
+            size: first.size,
+            path: first.path.clone(),
+            elapsed,
+            extension: first.extension.clone(),
+            warnings,
+            cached: false,
+            outputs,
+            selection: None,
         }
+    }
+}
+
+/// Encode every width × format of `variants` from one decode of `file_path`.
+fn compress_variants(
+    file_path: &str,
+    input_extension: ImageFormat,
+    options: &CompressOptions,
+    variants: &Variants,
+    warnings: &mut Vec<String>,
+) -> result::Result<Vec<Output>, CompressError> {
+    let extensions = if variants.formats.is_empty() {
+        vec![options.extension.clone()]
+    } else {
+        variants.formats.clone()
     };
+    let formats = extensions
+        .iter()
+        .map(|extension| {
+            let format = ImageFormat::from_extension(extension)
+                .ok_or_else(|| CompressError::UnsupportedExtension(extension.clone()))?;
+            if !input_extension.can_compress(&format) {
+                return Err(CompressError::Unsupported(input_extension, format));
+            }
+            Ok((extension.as_str(), format))
+        })
+        .collect::<result::Result<Vec<(&str, ImageFormat)>, CompressError>>()?;
 
-    Ok(result)
+    if input_extension == ImageFormat::Gif {
+        warnings.push(format!(
+            "only the first frame of {} is used for variants",
+            file_path
+        ));
+    }
+    let source = transform::apply(
+        decode(file_path, input_extension, &options.limits)?,
+        options,
+    );
+    let overlay = load_overlay(options)?;
+
+    // every variant is resized, so sources are never kept and jpegs are never transcoded losslessly
+    let formats = formats
+        .into_iter()
+        .map(|(extension, format)| {
+            let mut options = options.clone();
+            jpeg_policies(
+                file_path,
+                input_extension,
+                format,
+                &mut options,
+                true,
+                warnings,
+            );
+            (extension, format, options)
+        })
+        .collect::<Vec<(&str, ImageFormat, CompressOptions)>>();
+
+    let mut widths = variants
+        .widths
+        .iter()
+        .copied()
+        .filter(|width| *width <= source.width())
+        .collect::<Vec<u32>>();
+    if widths.len() < variants.widths.len() {
+        warnings.push(format!(
+            "widths above {}px are skipped for {}",
+            source.width(),
+            file_path
+        ));
+    }
+    if widths.is_empty() {
+        widths.push(source.width());
+    }
+
+    let suffix = options.suffix.as_deref().unwrap_or("");
+    let mut outputs = Vec::new();
+    for width in widths {
+        let resized = Resize {
+            max_width: Some(width),
+            max_height: None,
+        }
+        .apply(source.clone());
+        // watermark every size separately, so it keeps its share of the width and its margin
+        let resized = composite(resized, overlay.as_ref());
+        let suffix = format!("{}-{}w", suffix, width);
+
+        for (extension, format, options) in &formats {
+            let output_file_path =
+                output_file_path(file_path, extension, &suffix, &options.output)?;
+
+            let contents = encoder::encode(&resized, *format, options, file_path)?;
+            write_output(&output_file_path, &contents)?;
+
+            outputs.push(Output {
+                path: output_file_path,
+                size: contents.len() as u64,
+                extension: extension.to_string(),
+                format: *format,
+                width: resized.width(),
+                height: resized.height(),
+            });
+        }
+    }
+
+    Ok(outputs)
 }
 
 #[cfg(test)]
@@ -191,7 +992,214 @@ mod tests {
     #[test]
     fn test_set_file_to_same_dir() {
         let file_path = "src/assets/New-York-street-scene-with-steam-and-billboards.jpg";
-        let output_file_path = set_file_to_same_dir(&file_path, "jpg");
+        let output_file_path = set_file_to_same_dir(file_path, "jpg", "").unwrap();
         assert_eq!(file_path, &output_file_path);
     }
+
+    #[test]
+    fn test_set_file_to_same_dir_relative_file_name() {
+        let output_file_path = set_file_to_same_dir("photo.png", "webp", "-thumb").unwrap();
+        assert_eq!(output_file_path, "photo-thumb.webp");
+    }
+
+    #[test]
+    fn test_set_file_to_same_dir_without_file_name() {
+        let result = set_file_to_same_dir("/", "jpg", "");
+        assert!(matches!(result, Err(CompressError::InvalidPath { .. })));
+    }
+
+    #[test]
+    fn test_gif_to_animated_webp() {
+        let dir = std::env::temp_dir().join("ashuk-compresser-gif");
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("animation.gif");
+
+        let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].iter().map(|color| {
+            let buffer = image::RgbaImage::from_pixel(8, 8, image::Rgba(*color));
+            Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+        });
+        let mut gif = image::codecs::gif::GifEncoder::new(fs::File::create(&input).unwrap());
+        gif.encode_frames(frames).unwrap();
+        drop(gif);
+
+        let result = compress_to_target_extension(
+            &input.to_string_lossy(),
+            CompressOptions {
+                extension: "webp".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let contents = fs::read(&result.path).unwrap();
+        let features = webp::BitstreamFeatures::new(&contents).unwrap();
+        assert!(features.has_animation());
+
+        let limits = Limits {
+            max_frames: Some(1),
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode_frames(&input.to_string_lossy(), &limits),
+            Err(CompressError::LimitExceeded {
+                limit: Limit::Frames,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rules_applied_without_scheduler() {
+        let dir = std::env::temp_dir().join("ashuk-compresser-rules");
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.png");
+        image::RgbImage::from_pixel(8, 8, image::Rgb([20, 120, 200]))
+            .save(&input)
+            .unwrap();
+
+        let result = compress_to_target_extension(
+            &input.to_string_lossy(),
+            CompressOptions {
+                extension: "png".to_string(),
+                rules: vec![ConversionRule::new("png", "webp")],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(path::Path::new(&result.path).extension().unwrap(), "webp");
+    }
+
+    #[test]
+    fn test_empty_extension_keeps_format() {
+        let dir = std::env::temp_dir().join("ashuk-compresser-own-format");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.png");
+        image::RgbImage::from_pixel(8, 8, image::Rgb([20, 120, 200]))
+            .save(&input)
+            .unwrap();
+
+        let result = compress_to_target_extension(
+            &input.to_string_lossy(),
+            CompressOptions {
+                output: OutputLocation::Dir(dir.join("out")),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            result.path,
+            dir.join("out").join("photo.png").to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn test_variants_from_one_decode() {
+        let dir = std::env::temp_dir().join("ashuk-compresser-variants");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("hero.png");
+        image::RgbImage::from_fn(400, 200, |x, y| image::Rgb([x as u8, y as u8, 128]))
+            .save(&input)
+            .unwrap();
+
+        let result = compress_to_target_extension(
+            &input.to_string_lossy(),
+            CompressOptions {
+                extension: "webp".to_string(),
+                variants: Some(Variants {
+                    widths: vec![100, 200, 800],
+                    formats: vec!["webp".to_string(), "png".to_string()],
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let outputs = result
+            .outputs
+            .iter()
+            .map(|output| (output.width, output.height, output.extension.as_str()))
+            .collect::<Vec<(u32, u32, &str)>>();
+        assert_eq!(
+            outputs,
+            vec![
+                (100, 50, "webp"),
+                (100, 50, "png"),
+                (200, 100, "webp"),
+                (200, 100, "png")
+            ]
+        );
+        assert!(dir.join("hero-200w.png").exists());
+        assert_eq!(result.path, result.outputs[0].path);
+        assert_eq!(result.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_source_quality_policy() {
+        let dir = std::env::temp_dir().join("ashuk-compresser-source-quality");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.jpg");
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(160, 120, |x, y| {
+            image::Rgb([(x * 3) as u8, (y * 2) as u8, ((x ^ y) * 5) as u8])
+        }));
+        let source = encoder::encode_jpeg(&image, 60.0, true).unwrap();
+        fs::write(&input, &source).unwrap();
+
+        let compress = |source_quality| {
+            compress_to_target_extension(
+                &input.to_string_lossy(),
+                CompressOptions {
+                    quality: Some(90.0),
+                    extension: "jpg".to_string(),
+                    output: OutputLocation::Dir(dir.join("out")),
+                    source_quality,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let clamped = compress(SourceQuality::Clamp);
+        let output = fs::read(&clamped.path).unwrap();
+        assert_eq!(jpeg_quality::estimate(&output), Some(60.0));
+        assert_eq!(clamped.warnings.len(), 1);
+
+        let skipped = compress(SourceQuality::Skip);
+        assert_eq!(fs::read(&skipped.path).unwrap(), source);
+        assert_eq!(
+            (skipped.outputs[0].width, skipped.outputs[0].height),
+            (160, 120)
+        );
+
+        let ignored = compress(SourceQuality::Ignore);
+        let output = fs::read(&ignored.path).unwrap();
+        assert_eq!(jpeg_quality::estimate(&output), Some(90.0));
+        assert!(ignored.warnings.is_empty());
+
+        // variants are resized, so skip clamps them like any re-encode
+        let variants = compress_to_target_extension(
+            &input.to_string_lossy(),
+            CompressOptions {
+                quality: Some(90.0),
+                extension: "jpg".to_string(),
+                output: OutputLocation::Dir(dir.join("variants")),
+                source_quality: SourceQuality::Skip,
+                variants: Some(Variants {
+                    widths: vec![80, 160],
+                    formats: Vec::new(),
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(variants.outputs.len(), 2);
+        for output in &variants.outputs {
+            let contents = fs::read(&output.path).unwrap();
+            assert_eq!(jpeg_quality::estimate(&contents), Some(60.0));
+        }
+    }
 }
@@ -0,0 +1,352 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use crate::classify::ContentKind;
+use crate::compresser::{CompressOptions, OutputLocation, Variants};
+use crate::encoder::{EncoderSettings, JpegSettings, PngSettings, WebpSettings};
+use crate::jpegtran::JpegMarkers;
+use crate::rules::ConversionRule;
+use crate::transform::{Crop, Resize};
+use crate::watermark::Watermark;
+
+/// Project-local config, looked up from the working directory upwards.
+pub const PROJECT_CONFIG_FILE: &str = "ashuk.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to access config {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("config {path} is invalid: {source}")]
+    Parse {
+        path: String,
+        #[source]
+        source: toml::de::Error,
+    },
+    #[error("failed to serialize config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("unknown preset {0}")]
+    UnknownPreset(String),
+    #[error("user config directory is not available on this platform")]
+    NoConfigDir,
+}
+
+/// Where and under which name a preset writes its outputs.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct OutputRules {
+    pub dir: Option<PathBuf>,
+    /// appended to the file name, e.g. `-thumb`
+    pub suffix: Option<String>,
+}
+
+/// A named set of compression settings.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Preset {
+    /// target extension, keeps each file's own format when unset
+    pub format: Option<String>,
+    #[serde(default)]
+    pub jpeg: JpegSettings,
+    #[serde(default)]
+    pub webp: WebpSettings,
+    #[serde(default)]
+    pub png: PngSettings,
+    pub crop: Option<Crop>,
+    pub resize: Option<Resize>,
+    pub watermark: Option<Watermark>,
+    #[serde(default)]
+    pub output: OutputRules,
+    /// per-source-format routing, checked in order before `format`
+    #[serde(default)]
+    pub rules: Vec<ConversionRule>,
+    pub variants: Option<Variants>,
+}
+
+impl Preset {
+    /// Apply the preset on top of `options`. Explicit quality in `options` is dropped so per-format settings take effect.
+    pub fn apply(&self, options: &CompressOptions) -> CompressOptions {
+        let output = match &self.output.dir {
+            Some(dir) => OutputLocation::Dir(dir.clone()),
+            None => options.output.clone(),
+        };
+
+        CompressOptions {
+            quality: None,
+            extension: self.format.clone().unwrap_or_default(),
+            output,
+            suffix: self
+                .output
+                .suffix
+                .clone()
+                .or_else(|| options.suffix.clone()),
+            encoder: EncoderSettings {
+                jpeg: self.jpeg.clone(),
+                webp: self.webp.clone(),
+                png: self.png.clone(),
+            },
+            crop: self.crop,
+            resize: self.resize,
+            watermark: self.watermark.clone(),
+            rules: self.rules.clone(),
+            variants: self.variants.clone(),
+            ..options.clone()
+        }
+    }
+
+    /// Capture `options` as a preset, so the current settings can be saved under a name.
+    pub fn from_options(options: &CompressOptions) -> Self {
+        let mut encoder = options.encoder.clone();
+        if let Some(quality) = options.quality {
+            match options.extension.as_str() {
+                "png" => encoder.png.level = Some(quality as u8),
+                "webp" => encoder.webp.quality = Some(quality),
+                _ => encoder.jpeg.quality = Some(quality),
+            }
+        }
+
+        let dir = match &options.output {
+            OutputLocation::SameDir => None,
+            OutputLocation::Dir(dir) => Some(dir.clone()),
+        };
+
+        Preset {
+            format: Some(options.extension.clone()).filter(|format| !format.is_empty()),
+            jpeg: encoder.jpeg,
+            webp: encoder.webp,
+            png: encoder.png,
+            crop: options.crop,
+            resize: options.resize,
+            watermark: options.watermark.clone(),
+            output: OutputRules {
+                dir,
+                suffix: options.suffix.clone(),
+            },
+            rules: options.rules.clone(),
+            variants: options.variants.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Config {
+    /// preset applied when nothing else is selected
+    pub default_preset: Option<String>,
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl Config {
+    /// Presets shipped with ashuk, available without any config file.
+    pub fn builtin() -> Self {
+        let mut presets = BTreeMap::new();
+
+        presets.insert(
+            "web-hero".to_string(),
+            Preset {
+                format: Some("webp".to_string()),
+                webp: WebpSettings {
+                    quality: Some(82.0),
+                    lossless: false,
+                },
+                resize: Some(Resize {
+                    max_width: Some(1920),
+                    max_height: None,
+                }),
+                ..Default::default()
+            },
+        );
+        presets.insert(
+            "thumbnail".to_string(),
+            Preset {
+                format: Some("webp".to_string()),
+                webp: WebpSettings {
+                    quality: Some(70.0),
+                    lossless: false,
+                },
+                resize: Some(Resize {
+                    max_width: Some(320),
+                    max_height: Some(320),
+                }),
+                output: OutputRules {
+                    dir: None,
+                    suffix: Some("-thumb".to_string()),
+                },
+                ..Default::default()
+            },
+        );
+        presets.insert(
+            "archive-lossless".to_string(),
+            Preset {
+                format: None,
+                // jpegs keep their DCT coefficients, so their pixels don't change at all
+                jpeg: JpegSettings {
+                    quality: Some(100.0),
+                    progressive: true,
+                    lossless: true,
+                    markers: JpegMarkers::All,
+                },
+                webp: WebpSettings {
+                    quality: Some(100.0),
+                    lossless: true,
+                },
+                png: PngSettings { level: Some(6) },
+                ..Default::default()
+            },
+        );
+
+        presets.insert(
+            "web".to_string(),
+            Preset {
+                format: None,
+                rules: vec![
+                    ConversionRule {
+                        quality: Some(80.0),
+                        ..ConversionRule::new("jpg", "webp")
+                    },
+                    ConversionRule {
+                        alpha: Some(true),
+                        lossless: Some(true),
+                        ..ConversionRule::new("png", "webp")
+                    },
+                    // photos saved as png shrink far more as lossy webp
+                    ConversionRule {
+                        content: Some(ContentKind::Photo),
+                        quality: Some(80.0),
+                        ..ConversionRule::new("png", "webp")
+                    },
+                    ConversionRule::new("png", "png"),
+                    ConversionRule::new("gif", "webp"),
+                ],
+                ..Default::default()
+            },
+        );
+
+        Config {
+            default_preset: None,
+            presets,
+        }
+    }
+
+    pub fn from_file(path: &Path) -> result::Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_string_lossy().to_string(),
+            source,
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_string_lossy().to_string(),
+            source,
+        })
+    }
+
+    /// `ashuk/config.toml` inside the user's config directory.
+    pub fn user_path() -> result::Result<PathBuf, ConfigError> {
+        dirs::config_dir()
+            .map(|dir| dir.join("ashuk").join("config.toml"))
+            .ok_or(ConfigError::NoConfigDir)
+    }
+
+    /// Nearest `ashuk.toml` in `start` or one of its ancestors.
+    pub fn find_project(start: &Path) -> Option<PathBuf> {
+        start
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+            .find(|path| path.is_file())
+    }
+
+    /// Built-in presets, overridden by the user config, overridden by the project config found from `start`.
+    pub fn load(start: &Path) -> result::Result<Self, ConfigError> {
+        let mut config = Config::builtin();
+
+        if let Ok(user_path) = Config::user_path() {
+            if user_path.is_file() {
+                config.merge(Config::from_file(&user_path)?);
+            }
+        }
+        if let Some(project_path) = Config::find_project(start) {
+            config.merge(Config::from_file(&project_path)?);
+        }
+
+        Ok(config)
+    }
+
+    /// Add `other`'s presets, replacing presets with the same name.
+    pub fn merge(&mut self, other: Config) {
+        if other.default_preset.is_some() {
+            self.default_preset = other.default_preset;
+        }
+        self.presets.extend(other.presets);
+    }
+
+    pub fn preset(&self, name: &str) -> result::Result<&Preset, ConfigError> {
+        self.presets
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownPreset(name.to_string()))
+    }
+
+    pub fn default_preset(&self) -> Option<&Preset> {
+        self.default_preset
+            .as_ref()
+            .and_then(|name| self.presets.get(name))
+    }
+
+    pub fn save(&self, path: &Path) -> result::Result<(), ConfigError> {
+        let contents = toml::to_string_pretty(self)?;
+        let io_error = |source| ConfigError::Io {
+            path: path.to_string_lossy().to_string(),
+            source,
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        fs::write(path, contents).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_project_config_overrides_builtin() {
+        let contents = r#"
+            default_preset = "thumbnail"
+
+            [presets.thumbnail]
+            format = "jpg"
+            jpeg = { quality = 60, progressive = false }
+            resize = { max_width = 200, max_height = 200 }
+            output = { suffix = "-small" }
+        "#;
+        let project: Config = toml::from_str(contents).unwrap();
+
+        let mut config = Config::builtin();
+        config.merge(project);
+
+        let options = config.default_preset().unwrap().apply(&CompressOptions {
+            quality: Some(90.0),
+            extension: "webp".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(options.extension, "jpg");
+        assert_eq!(options.quality, None);
+        assert_eq!(options.encoder.jpeg.quality, Some(60.0));
+        assert!(!options.encoder.jpeg.progressive);
+        assert_eq!(options.suffix.as_deref(), Some("-small"));
+
+        // untouched builtin presets stay available
+        assert!(config.preset("web-hero").is_ok());
+        assert!(matches!(
+            config.preset("missing"),
+            Err(ConfigError::UnknownPreset(_))
+        ));
+    }
+}
@@ -0,0 +1,241 @@
+use image::DynamicImage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::result;
+
+use crate::compresser::{CompressError, CompressOptions};
+use crate::encoder;
+use crate::format_meta::ImageFormat;
+use crate::metrics;
+
+// side of the tiles sampled from large images
+const TILE_SIZE: u32 = 256;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct CurvePoint {
+    pub quality: f32,
+    /// predicted size of the whole output
+    pub bytes: u64,
+    /// 1 for a lossless result
+    pub ssim: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CurveOptions {
+    /// qualities to sample, evenly spread over the format's range when empty
+    pub qualities: Vec<f32>,
+    /// number of samples when `qualities` is empty
+    pub steps: u32,
+    /// images with more pixels are estimated from tiles covering about this many pixels
+    pub sample_pixels: u64,
+}
+
+impl Default for CurveOptions {
+    fn default() -> Self {
+        Self {
+            qualities: Vec::new(),
+            steps: 11,
+            sample_pixels: 512 * 512,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct QualityCurve {
+    pub format: ImageFormat,
+    pub points: Vec<CurvePoint>,
+    /// sizes are extrapolated from tiles rather than measured on the whole image
+    pub estimated: bool,
+}
+
+// the quality `format` is actually encoded at, png levels are whole numbers up to 6
+fn effective_quality(format: ImageFormat, quality: f32) -> f32 {
+    match format {
+        ImageFormat::Png => (quality.max(0.0) as u8).min(6) as f32,
+        _ => quality,
+    }
+}
+
+impl CurveOptions {
+    /// Qualities sampled for `format`, ascending and without two encoding the same way.
+    fn qualities(&self, format: ImageFormat) -> Vec<f32> {
+        let mut qualities = if !self.qualities.is_empty() {
+            self.qualities.clone()
+        } else {
+            let context = format.get_compress_options_context();
+            let steps = self.steps.max(2);
+            (0..steps)
+                .map(|i| context.min + (context.max - context.min) * i as f32 / (steps - 1) as f32)
+                // whole numbers, png levels can't be fractional anyway
+                .map(f32::round)
+                .collect()
+        };
+
+        qualities = qualities
+            .into_iter()
+            .map(|quality| effective_quality(format, quality))
+            .collect();
+        qualities.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        qualities.dedup();
+        qualities
+    }
+}
+
+/// Tiles at native resolution spread over the image, so detail density matches the full encode.
+fn sample_tiles(image: &DynamicImage, sample_pixels: u64) -> Vec<DynamicImage> {
+    let (width, height) = (image.width(), image.height());
+    if width as u64 * height as u64 <= sample_pixels {
+        return vec![image.clone()];
+    }
+
+    let tile_width = TILE_SIZE.min(width);
+    let tile_height = TILE_SIZE.min(height);
+    let count = (sample_pixels / (tile_width as u64 * tile_height as u64)).max(1);
+    let per_side = (count as f64).sqrt().ceil() as u32;
+
+    let mut tiles = Vec::new();
+    for row in 0..per_side {
+        for column in 0..per_side {
+            // tile centres at the middle of each grid cell
+            let x = (width - tile_width) as u64 * (2 * column as u64 + 1) / (2 * per_side as u64);
+            let y = (height - tile_height) as u64 * (2 * row as u64 + 1) / (2 * per_side as u64);
+            tiles.push(image.crop_imm(x as u32, y as u32, tile_width, tile_height));
+        }
+    }
+    tiles
+}
+
+/// Encode `image` at a range of qualities in `format` and report the size and SSIM of each.
+///
+/// `image` should already be transformed as it would be for the real output.
+pub fn quality_curve(
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &CompressOptions,
+    curve: &CurveOptions,
+) -> result::Result<QualityCurve, CompressError> {
+    let tiles = sample_tiles(image, curve.sample_pixels);
+    let sampled_pixels = tiles
+        .iter()
+        .map(|tile| tile.width() as u64 * tile.height() as u64)
+        .sum::<u64>();
+    let scale = (image.width() as u64 * image.height() as u64) as f64 / sampled_pixels as f64;
+    let estimated = tiles.len() > 1 || scale > 1.0;
+    let originals = tiles.iter().map(|tile| tile.to_rgba8()).collect::<Vec<_>>();
+
+    let points = curve
+        .qualities(format)
+        .into_par_iter()
+        .map(|quality| {
+            let options = CompressOptions {
+                quality: Some(quality),
+                ..options.clone()
+            };
+
+            // every tile carries its own headers, the whole output only once
+            let overhead = if estimated {
+                encoder::encode(&image.crop_imm(0, 0, 1, 1), format, &options, "curve")?.len()
+                    as u64
+            } else {
+                0
+            };
+
+            let (mut bytes, mut ssim) = (0, 0.0);
+            for (tile, original) in tiles.iter().zip(&originals) {
+                let encoded = encoder::encode(tile, format, &options, "curve")?;
+                let decoded =
+                    image::load_from_memory_with_format(&encoded, format.to_image_format())
+                        .map_err(|source| CompressError::Decode {
+                            path: "curve".to_string(),
+                            source,
+                        })?;
+
+                bytes += encoded.len() as u64;
+                ssim += metrics::ssim_with_alpha(original, &decoded.to_rgba8());
+            }
+
+            Ok(CurvePoint {
+                quality,
+                bytes: (bytes.saturating_sub(overhead * tiles.len() as u64) as f64 * scale).round()
+                    as u64
+                    + overhead,
+                ssim: ssim / tiles.len() as f64,
+            })
+        })
+        .collect::<result::Result<Vec<CurvePoint>, CompressError>>()?;
+
+    Ok(QualityCurve {
+        format,
+        points,
+        estimated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_quality_curve_is_monotonic() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(600, 400, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8])
+        }));
+        let options = CompressOptions {
+            extension: "jpg".to_string(),
+            ..Default::default()
+        };
+        let curve = CurveOptions {
+            qualities: vec![20.0, 60.0, 95.0],
+            sample_pixels: 256 * 256 * 2,
+            ..Default::default()
+        };
+
+        let result = quality_curve(&image, ImageFormat::Jpeg, &options, &curve).unwrap();
+        assert!(result.estimated);
+        assert_eq!(result.points.len(), 3);
+        for pair in result.points.windows(2) {
+            assert!(pair[0].bytes < pair[1].bytes);
+            assert!(pair[0].ssim < pair[1].ssim);
+        }
+    }
+
+    #[test]
+    fn test_flat_image_estimate() {
+        // almost all of a flat image's bytes are headers, which the output only has once
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            2000,
+            2000,
+            image::Rgb([90, 140, 200]),
+        ));
+        let options = CompressOptions {
+            extension: "jpg".to_string(),
+            ..Default::default()
+        };
+        let curve = CurveOptions {
+            qualities: vec![75.0],
+            ..Default::default()
+        };
+
+        let result = quality_curve(&image, ImageFormat::Jpeg, &options, &curve).unwrap();
+        let actual = encoder::encode(
+            &image,
+            ImageFormat::Jpeg,
+            &CompressOptions {
+                quality: Some(75.0),
+                ..options
+            },
+            "curve",
+        )
+        .unwrap()
+        .len() as f64;
+        let predicted = result.points[0].bytes as f64;
+        assert!((predicted - actual).abs() / actual < 0.2);
+
+        let png = CurveOptions {
+            qualities: vec![2.0, 2.4, 9.0, 6.0],
+            ..Default::default()
+        };
+        assert_eq!(png.qualities(ImageFormat::Png), vec![2.0, 6.0]);
+    }
+}
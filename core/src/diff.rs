@@ -0,0 +1,319 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::Path;
+use std::result;
+
+use crate::compresser::{self, CompressError, CompressOptions, Stage};
+use crate::encoder;
+use crate::format_meta::{FormatDetection, ImageFormat};
+use crate::limits::Limits;
+use crate::metrics::{self, ErrorStats};
+
+// gap between the panels of a side-by-side image
+const PANEL_GAP: u32 = 4;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct DiffOptions {
+    /// regions per row for the error statistics
+    pub columns: u32,
+    /// regions per column for the error statistics
+    pub rows: u32,
+    /// multiplies pixel errors before coloring, so small errors stay visible
+    pub gain: f32,
+    pub detection: FormatDetection,
+    pub limits: Limits,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            columns: 8,
+            rows: 8,
+            gain: 4.0,
+            detection: FormatDetection::Content,
+            limits: Limits::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct RegionStats {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(flatten)]
+    pub stats: ErrorStats,
+}
+
+/// Comparison of an original and its compressed version.
+pub struct Diff {
+    pub original: RgbImage,
+    pub compressed: RgbImage,
+    /// per-pixel error colored from blue (small) to red (large) over a dimmed original
+    pub heatmap: RgbImage,
+    pub overall: ErrorStats,
+    /// regions in reading order, `columns` × `rows`
+    pub regions: Vec<RegionStats>,
+    pub warnings: Vec<String>,
+}
+
+/// What `Diff::write` produced, for the app or a QA report.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DiffReport {
+    pub heatmap_path: String,
+    pub side_by_side_path: String,
+    pub width: u32,
+    pub height: u32,
+    pub overall: ErrorStats,
+    pub regions: Vec<RegionStats>,
+    pub warnings: Vec<String>,
+}
+
+/// Decode both files and compare them.
+pub fn compare(
+    original_path: &str,
+    compressed_path: &str,
+    options: &DiffOptions,
+) -> result::Result<Diff, CompressError> {
+    // a diff of a file against itself is always clean, the output most likely overwrote the input
+    let canonical =
+        |path: &str| fs::canonicalize(path).map_err(CompressError::io(path, Stage::Read));
+    if canonical(original_path)? == canonical(compressed_path)? {
+        return Err(CompressError::SameFile {
+            path: compressed_path.to_string(),
+        });
+    }
+
+    let decode = |path: &str| {
+        let detected = compresser::detect(path, options.detection)?;
+        compresser::decode(path, detected.format, &options.limits)
+    };
+
+    Ok(compare_images(
+        &decode(original_path)?,
+        &decode(compressed_path)?,
+        options,
+    ))
+}
+
+/// Compare two images. An original of another size is resized to the compressed one first.
+pub fn compare_images(
+    original: &DynamicImage,
+    compressed: &DynamicImage,
+    options: &DiffOptions,
+) -> Diff {
+    let compressed = compressed.to_rgb8();
+    let (width, height) = compressed.dimensions();
+
+    let mut warnings = Vec::new();
+    let original = if original.width() != width || original.height() != height {
+        warnings.push(format!(
+            "original is {}x{} and was resized to {}x{} for the comparison",
+            original.width(),
+            original.height(),
+            width,
+            height
+        ));
+        imageops::resize(&original.to_rgb8(), width, height, FilterType::Triangle)
+    } else {
+        original.to_rgb8()
+    };
+
+    let heatmap = RgbImage::from_fn(width, height, |x, y| {
+        let a = original.get_pixel(x, y).0;
+        let b = compressed.get_pixel(x, y).0;
+        let error = (0..3)
+            .map(|channel| (a[channel] as i32 - b[channel] as i32).unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        let level = (error as f32 * options.gain / 255.0).min(1.0);
+
+        // dimmed luma of the original keeps the picture recognizable under the heat
+        let luma = (0.299 * a[0] as f32 + 0.587 * a[1] as f32 + 0.114 * a[2] as f32) * 0.3;
+        let heat = heat_color(level);
+        Rgb([0, 1, 2].map(|channel| (luma * (1.0 - level) + heat[channel] * level) as u8))
+    });
+
+    let columns = options.columns.clamp(1, width.max(1));
+    let rows = options.rows.clamp(1, height.max(1));
+    let mut regions = Vec::new();
+    for row in 0..rows {
+        for column in 0..columns {
+            let (x, y) = (column * width / columns, row * height / rows);
+            let region_width = (column + 1) * width / columns - x;
+            let region_height = (row + 1) * height / rows - y;
+            regions.push(RegionStats {
+                x,
+                y,
+                width: region_width,
+                height: region_height,
+                stats: metrics::error_stats(
+                    &original,
+                    &compressed,
+                    x,
+                    y,
+                    region_width,
+                    region_height,
+                ),
+            });
+        }
+    }
+
+    Diff {
+        overall: metrics::error_stats(&original, &compressed, 0, 0, width, height),
+        original,
+        compressed,
+        heatmap,
+        regions,
+        warnings,
+    }
+}
+
+/// Blue through green and yellow to red.
+fn heat_color(level: f32) -> [f32; 3] {
+    const STOPS: [[f32; 3]; 4] = [
+        [0.0, 0.0, 255.0],
+        [0.0, 255.0, 0.0],
+        [255.0, 255.0, 0.0],
+        [255.0, 0.0, 0.0],
+    ];
+
+    let position = level.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let index = (position as usize).min(STOPS.len() - 2);
+    let t = position - index as f32;
+    let (from, to) = (STOPS[index], STOPS[index + 1]);
+    [0, 1, 2].map(|channel| from[channel] + (to[channel] - from[channel]) * t)
+}
+
+impl Diff {
+    /// Original, compressed and heatmap next to each other.
+    pub fn side_by_side(&self) -> RgbImage {
+        let (width, height) = self.compressed.dimensions();
+        let mut canvas =
+            RgbImage::from_pixel(width * 3 + PANEL_GAP * 2, height, Rgb([255, 255, 255]));
+
+        for (i, panel) in [&self.original, &self.compressed, &self.heatmap]
+            .iter()
+            .enumerate()
+        {
+            let x = i as u32 * (width + PANEL_GAP);
+            imageops::replace(&mut canvas, *panel, x as i64, 0);
+        }
+
+        canvas
+    }
+
+    /// Regions sorted from the most to the least damaged.
+    pub fn worst_regions(&self) -> Vec<RegionStats> {
+        let mut regions = self.regions.clone();
+        regions.sort_by(|a, b| {
+            b.stats
+                .mse
+                .partial_cmp(&a.stats.mse)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        regions
+    }
+
+    /// Encode the heatmap and the side-by-side image, each in the format of its extension.
+    pub fn write(
+        &self,
+        heatmap_path: &str,
+        side_by_side_path: &str,
+        options: &CompressOptions,
+    ) -> result::Result<DiffReport, CompressError> {
+        write_image(
+            DynamicImage::ImageRgb8(self.heatmap.clone()),
+            heatmap_path,
+            options,
+        )?;
+        write_image(
+            DynamicImage::ImageRgb8(self.side_by_side()),
+            side_by_side_path,
+            options,
+        )?;
+
+        Ok(DiffReport {
+            heatmap_path: heatmap_path.to_string(),
+            side_by_side_path: side_by_side_path.to_string(),
+            width: self.compressed.width(),
+            height: self.compressed.height(),
+            overall: self.overall,
+            regions: self.regions.clone(),
+            warnings: self.warnings.clone(),
+        })
+    }
+}
+
+fn write_image(
+    image: DynamicImage,
+    path: &str,
+    options: &CompressOptions,
+) -> result::Result<(), CompressError> {
+    let format = ImageFormat::from_path(Path::new(path))
+        .ok()
+        .filter(|format| ImageFormat::get_formats().contains(format))
+        .ok_or_else(|| CompressError::UnsupportedExtension(path.to_string()))?;
+
+    let contents = encoder::encode(&image, format, options, path)?;
+    fs::write(path, contents).map_err(CompressError::io(path, Stage::Write))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_compare_images_regions() {
+        let original = RgbImage::from_pixel(80, 40, Rgb([120, 120, 120]));
+        let mut compressed = original.clone();
+        // damage the bottom right corner only
+        for y in 20..40 {
+            for x in 40..80 {
+                compressed.put_pixel(x, y, Rgb([160, 120, 120]));
+            }
+        }
+
+        let diff = compare_images(
+            &DynamicImage::ImageRgb8(original),
+            &DynamicImage::ImageRgb8(compressed),
+            &DiffOptions {
+                columns: 2,
+                rows: 2,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(diff.regions.len(), 4);
+        assert_eq!(diff.regions[0].stats.max_error, 0);
+        assert_eq!(diff.regions[3].stats.max_error, 40);
+        assert_eq!(
+            (diff.worst_regions()[0].x, diff.worst_regions()[0].y),
+            (40, 20)
+        );
+        assert_eq!(diff.heatmap.get_pixel(0, 0).0, [36, 36, 36]);
+        assert_eq!(diff.side_by_side().width(), 80 * 3 + PANEL_GAP * 2);
+    }
+
+    #[test]
+    fn test_compare_same_file() {
+        let dir = std::env::temp_dir().join("ashuk-diff-same");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("photo.png");
+        RgbImage::from_pixel(4, 4, Rgb([10, 20, 30]))
+            .save(&path)
+            .unwrap();
+        let alias = dir.join(".").join("photo.png");
+
+        let result = compare(
+            &path.to_string_lossy(),
+            &alias.to_string_lossy(),
+            &DiffOptions::default(),
+        );
+        assert!(matches!(result, Err(CompressError::SameFile { .. })));
+    }
+}
@@ -0,0 +1,268 @@
+use image::codecs::png::PngEncoder;
+use image::{DynamicImage, Frame, ImageEncoder};
+use mozjpeg::{ColorSpace, Compress, ScanMode};
+use serde::{Deserialize, Serialize};
+
+use std::any::Any;
+use std::panic;
+use std::result;
+
+use crate::compresser::{CompressError, CompressOptions};
+use crate::format_meta::ImageFormat;
+use crate::jpegtran::JpegMarkers;
+
+/// Largest width or height libjpeg can write.
+pub const JPEG_MAX_DIMENSION: u32 = 65500;
+
+fn default_progressive() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JpegSettings {
+    pub quality: Option<f32>,
+    #[serde(default = "default_progressive")]
+    pub progressive: bool,
+    /// jpg sources are rewritten from their DCT coefficients instead of re-encoded, when no transform changes pixels
+    #[serde(default)]
+    pub lossless: bool,
+    /// markers kept by lossless rewrites, re-encoding always drops them
+    #[serde(default)]
+    pub markers: JpegMarkers,
+}
+
+impl Default for JpegSettings {
+    fn default() -> Self {
+        Self {
+            quality: None,
+            progressive: default_progressive(),
+            lossless: false,
+            markers: JpegMarkers::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct WebpSettings {
+    pub quality: Option<f32>,
+    #[serde(default)]
+    pub lossless: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct PngSettings {
+    /// oxipng optimization level, 0-6
+    pub level: Option<u8>,
+}
+
+/// Settings for each output format, used when `CompressOptions::quality` doesn't override them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct EncoderSettings {
+    #[serde(default)]
+    pub jpeg: JpegSettings,
+    #[serde(default)]
+    pub webp: WebpSettings,
+    #[serde(default)]
+    pub png: PngSettings,
+}
+
+impl CompressOptions {
+    /// Quality for `format`: the explicit quality, then the per-format setting, then the format default.
+    pub fn quality_for(&self, format: ImageFormat) -> f32 {
+        let configured = match format {
+            ImageFormat::Jpeg => self.encoder.jpeg.quality,
+            ImageFormat::WebP => self.encoder.webp.quality,
+            ImageFormat::Png => self.encoder.png.level.map(f32::from),
+            ImageFormat::Gif => None,
+        };
+
+        self.quality
+            .or(configured)
+            .unwrap_or_else(|| format.get_compress_options_context().default_value)
+    }
+}
+
+/// Encode `image` as `format`, `path` names the output in errors.
+pub fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    options: &CompressOptions,
+    path: &str,
+) -> result::Result<Vec<u8>, CompressError> {
+    let quality = options.quality_for(format);
+
+    if format == ImageFormat::Jpeg
+        && (image.width() > JPEG_MAX_DIMENSION || image.height() > JPEG_MAX_DIMENSION)
+    {
+        return Err(CompressError::Dimensions {
+            path: path.to_string(),
+            codec: format,
+            width: image.width(),
+            height: image.height(),
+            max: JPEG_MAX_DIMENSION,
+        });
+    }
+
+    match format {
+        ImageFormat::Jpeg => encode_jpeg(image, quality, options.encoder.jpeg.progressive),
+        ImageFormat::WebP => encode_webp(image, quality, options.encoder.webp.lossless),
+        ImageFormat::Png => encode_png(image, quality as u8),
+        ImageFormat::Gif => Err("writing gif is not supported".to_string()),
+    }
+    .map_err(CompressError::codec(path, format))
+}
+
+pub fn encode_webp(
+    image: &DynamicImage,
+    quality: f32,
+    lossless: bool,
+) -> result::Result<Vec<u8>, String> {
+    // convert to a pixel layout libwebp accepts instead of rejecting e.g. 16-bit or grayscale input
+    let contents = if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+            .encode_simple(lossless, quality)
+            .map(|memory| memory.to_vec())
+    } else {
+        let rgb = image.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+            .encode_simple(lossless, quality)
+            .map(|memory| memory.to_vec())
+    };
+
+    contents.map_err(|err| format!("{:?}", err))
+}
+
+/// Encode frames into an animated webp, keeping each frame's delay.
+pub fn encode_webp_animation(
+    frames: &[Frame],
+    quality: f32,
+    lossless: bool,
+) -> result::Result<Vec<u8>, String> {
+    let first = frames
+        .first()
+        .ok_or_else(|| "animation has no frames".to_string())?;
+    let (width, height) = first.buffer().dimensions();
+
+    let mut config = webp::WebPConfig::new().map_err(|_| "invalid webp config".to_string())?;
+    config.lossless = lossless as i32;
+    config.quality = quality;
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    let mut timestamp = 0;
+    for frame in frames {
+        let buffer = frame.buffer();
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            buffer.as_raw(),
+            buffer.width(),
+            buffer.height(),
+            timestamp,
+        ));
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        timestamp += (numerator / denominator.max(1)) as i32;
+    }
+
+    encoder
+        .try_encode()
+        .map(|memory| memory.to_vec())
+        .map_err(|err| format!("{:?}", err))
+}
+
+pub fn encode_jpeg(
+    image: &DynamicImage,
+    quality: f32,
+    progressive: bool,
+) -> result::Result<Vec<u8>, String> {
+    // libjpeg errors can't unwind out of its extern "C" calls and abort the process,
+    // so sizes it rejects never reach it
+    if image.width() == 0 || image.height() == 0 {
+        return Err("image is empty".to_string());
+    }
+    if image.width() > JPEG_MAX_DIMENSION || image.height() > JPEG_MAX_DIMENSION {
+        return Err(format!(
+            "{}x{} is over the {} pixel jpeg limit",
+            image.width(),
+            image.height(),
+            JPEG_MAX_DIMENSION
+        ));
+    }
+    let rgb = image.to_rgb8();
+
+    // the mozjpeg wrapper panics on misuse, e.g. a short scanline buffer
+    panic::catch_unwind(|| {
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_scan_optimization_mode(ScanMode::AllComponentsTogether);
+        // mozjpeg defaults to progressive, dropping the scan script gives a baseline file
+        if !progressive {
+            comp.set_optimize_scans(false);
+        }
+        comp.set_quality(quality);
+
+        comp.set_size(rgb.width() as usize, rgb.height() as usize);
+
+        comp.set_mem_dest();
+        comp.start_compress();
+
+        if !comp.write_scanlines(rgb.as_raw()) {
+            return Err("failed to write scanlines".to_string());
+        }
+
+        comp.finish_compress();
+        comp.data_to_vec()
+            .map_err(|_| "encoder produced no data".to_string())
+    })
+    .unwrap_or_else(|payload| Err(panic_message(payload)))
+}
+
+/// Encode to PNG and optimize the result with oxipng at `level`.
+pub fn encode_png(image: &DynamicImage, level: u8) -> result::Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    PngEncoder::new(&mut contents)
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color(),
+        )
+        .map_err(|err| err.to_string())?;
+
+    oxipng::optimize_from_memory(&contents, &oxipng::Options::from_preset(level))
+        .map_err(|err| err.to_string())
+}
+
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_jpeg_over_dimension_limit() {
+        let image = DynamicImage::new_rgb8(JPEG_MAX_DIMENSION + 1, 1);
+        let err = encode(
+            &image,
+            ImageFormat::Jpeg,
+            &CompressOptions::default(),
+            "wide.jpg",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            CompressError::Dimensions {
+                width: 65501,
+                max: JPEG_MAX_DIMENSION,
+                ..
+            }
+        ));
+        assert!(encode_jpeg(&image, 80.0, true).is_err());
+
+        let image = DynamicImage::new_rgb8(JPEG_MAX_DIMENSION, 1);
+        assert!(encode_jpeg(&image, 80.0, true).is_ok());
+    }
+}
@@ -3,18 +3,25 @@ use thiserror::Error;
 
 use std::ffi::OsStr;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
 use std::path::Path;
 
+// longest signature checked by `ImageFormat::from_signature` (RIFF....WEBP)
+const SIGNATURE_LEN: usize = 12;
+
 pub enum ProcessStrategy {
     Serial,
     Parallel,
 }
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum ImageFormat {
     Png,
     Jpeg,
     WebP,
+    /// input only, converted to animated webp
+    Gif,
 }
 
 impl fmt::Display for ImageFormat {
@@ -27,6 +34,31 @@ impl fmt::Display for ImageFormat {
 pub enum ImageFormatError {
     #[error("file is not supported")]
     Unsupported,
+    #[error("failed to read file header: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// How the format of an input file is determined.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum FormatDetection {
+    /// Sniff the file signature, falling back to the extension when it is not recognized.
+    #[default]
+    Content,
+    /// Trust the file extension only.
+    Extension,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedFormat {
+    pub format: ImageFormat,
+    /// format implied by the file extension, if it names a supported one
+    pub extension_format: Option<ImageFormat>,
+}
+
+impl DetectedFormat {
+    pub fn is_mismatched(&self) -> bool {
+        matches!(self.extension_format, Some(ext) if ext != self.format)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -50,6 +82,7 @@ impl ImageFormat {
                 "jpg" | "jpeg" => ImageFormat::Jpeg,
                 "png" => ImageFormat::Png,
                 "webp" => ImageFormat::WebP,
+                "gif" => ImageFormat::Gif,
                 _ => return None,
             })
         }
@@ -65,45 +98,131 @@ impl ImageFormat {
             let exact_ext = path.extension();
             exact_ext
                 .and_then(ImageFormat::from_extension)
-                .ok_or_else(|| ImageFormatError::Unsupported)
+                .ok_or(ImageFormatError::Unsupported)
         }
 
         inner(path.as_ref())
     }
 
+    pub fn from_signature(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(ImageFormat::Png),
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageFormat::Jpeg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+                Some(ImageFormat::WebP)
+            }
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+
+    pub fn from_content<P>(path: P) -> Result<Self, ImageFormatError>
+    where
+        P: AsRef<Path>,
+    {
+        fn inner(path: &Path) -> Result<ImageFormat, ImageFormatError> {
+            let mut header = Vec::with_capacity(SIGNATURE_LEN);
+            File::open(path)?
+                .take(SIGNATURE_LEN as u64)
+                .read_to_end(&mut header)?;
+            ImageFormat::from_signature(&header).ok_or(ImageFormatError::Unsupported)
+        }
+
+        inner(path.as_ref())
+    }
+
+    pub fn detect<P>(
+        path: P,
+        detection: FormatDetection,
+    ) -> Result<DetectedFormat, ImageFormatError>
+    where
+        P: AsRef<Path>,
+    {
+        fn inner(
+            path: &Path,
+            detection: FormatDetection,
+        ) -> Result<DetectedFormat, ImageFormatError> {
+            let extension_format = ImageFormat::from_path(path).ok();
+            let format = match detection {
+                FormatDetection::Content => match ImageFormat::from_content(path) {
+                    Ok(format) => format,
+                    // unknown signature, fall back to extension
+                    Err(ImageFormatError::Unsupported) => {
+                        extension_format.ok_or(ImageFormatError::Unsupported)?
+                    }
+                    Err(err) => return Err(err),
+                },
+                FormatDetection::Extension => {
+                    extension_format.ok_or(ImageFormatError::Unsupported)?
+                }
+            };
+
+            Ok(DetectedFormat {
+                format,
+                extension_format,
+            })
+        }
+
+        inner(path.as_ref(), detection)
+    }
+
+    pub fn to_image_format(self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::WebP => image::ImageFormat::WebP,
+            ImageFormat::Gif => image::ImageFormat::Gif,
+        }
+    }
+
     pub fn extensions_str(self) -> &'static [&'static str] {
         match self {
             ImageFormat::Png => &["png"],
             ImageFormat::Jpeg => &["jpg", "jpeg"],
             ImageFormat::WebP => &["webp"],
+            ImageFormat::Gif => &["gif"],
         }
     }
 
     pub fn get_representative_ext_str(&self) -> String {
         match self {
-            ImageFormat::Png => <str as ToString>::to_string(&*self.extensions_str()[0]),
-            ImageFormat::Jpeg => <str as ToString>::to_string(&*self.extensions_str()[0]),
-            ImageFormat::WebP => <str as ToString>::to_string(&*self.extensions_str()[0]),
+            ImageFormat::Png => <str as ToString>::to_string(self.extensions_str()[0]),
+            ImageFormat::Jpeg => <str as ToString>::to_string(self.extensions_str()[0]),
+            ImageFormat::WebP => <str as ToString>::to_string(self.extensions_str()[0]),
+            ImageFormat::Gif => <str as ToString>::to_string(self.extensions_str()[0]),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::WebP => "image/webp",
+            ImageFormat::Gif => "image/gif",
         }
     }
 
     pub fn can_compress(&self, target: &ImageFormat) -> bool {
         match self {
-            &ImageFormat::Jpeg => match target {
-                &ImageFormat::Jpeg => true,
-                &ImageFormat::Png => false,
-                &ImageFormat::WebP => true,
+            ImageFormat::Jpeg => match target {
+                ImageFormat::Jpeg => true,
+                ImageFormat::Png => false,
+                ImageFormat::WebP => true,
+                ImageFormat::Gif => false,
             },
-            &ImageFormat::Png => match target {
-                &ImageFormat::Jpeg => false,
-                &ImageFormat::Png => true,
-                &ImageFormat::WebP => true,
+            ImageFormat::Png => match target {
+                ImageFormat::Jpeg => false,
+                ImageFormat::Png => true,
+                ImageFormat::WebP => true,
+                ImageFormat::Gif => false,
             },
-            &ImageFormat::WebP => match target {
-                &ImageFormat::Jpeg => true,
-                &ImageFormat::Png => false,
-                &ImageFormat::WebP => true,
+            ImageFormat::WebP => match target {
+                ImageFormat::Jpeg => true,
+                ImageFormat::Png => false,
+                ImageFormat::WebP => true,
+                ImageFormat::Gif => false,
             },
+            ImageFormat::Gif => matches!(target, ImageFormat::WebP),
         }
     }
 
@@ -112,6 +231,7 @@ impl ImageFormat {
             ImageFormat::Png => ProcessStrategy::Serial,
             ImageFormat::Jpeg => ProcessStrategy::Parallel,
             ImageFormat::WebP => ProcessStrategy::Parallel,
+            ImageFormat::Gif => ProcessStrategy::Parallel,
         }
     }
 
@@ -120,8 +240,13 @@ impl ImageFormat {
         let png = ImageFormat::Png;
         let webp = ImageFormat::WebP;
 
-        let formats = vec![jpg, png, webp];
+        vec![jpg, png, webp]
+    }
 
+    /// Formats accepted as input, which includes formats that can't be written.
+    pub fn get_input_formats() -> Vec<ImageFormat> {
+        let mut formats = ImageFormat::get_formats();
+        formats.push(ImageFormat::Gif);
         formats
     }
 
@@ -141,7 +266,7 @@ impl ImageFormat {
                 default_value: 75.0,
                 step: 0.1,
             },
-            ImageFormat::WebP => CompressOptionsContext {
+            ImageFormat::WebP | ImageFormat::Gif => CompressOptionsContext {
                 extension: self.get_representative_ext_str(),
                 min: 0.0,
                 max: 100.0,
@@ -151,3 +276,44 @@ impl ImageFormat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_from_signature() {
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 13];
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0];
+        let webp = *b"RIFF\x24\x00\x00\x00WEBPVP8 ";
+        let wave = *b"RIFF\x24\x00\x00\x00WAVEfmt ";
+        assert_eq!(ImageFormat::from_signature(&png), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_signature(&jpeg), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::from_signature(&webp), Some(ImageFormat::WebP));
+        assert_eq!(
+            ImageFormat::from_signature(b"GIF89a"),
+            Some(ImageFormat::Gif)
+        );
+        assert_eq!(ImageFormat::from_signature(&wave), None);
+        assert_eq!(ImageFormat::from_signature(&[0xFF, 0xD8]), None);
+    }
+
+    #[test]
+    fn test_detect_mismatched_extension() {
+        let path = std::env::temp_dir().join("ashuk-detect-mismatched.jpg");
+        std::fs::copy(
+            "src/assets/New-York-street-scene-with-steam-and-billboards.webp",
+            &path,
+        )
+        .unwrap();
+
+        let detected = ImageFormat::detect(&path, FormatDetection::Content).unwrap();
+        assert_eq!(detected.format, ImageFormat::WebP);
+        assert_eq!(detected.extension_format, Some(ImageFormat::Jpeg));
+        assert!(detected.is_mismatched());
+
+        let detected = ImageFormat::detect(&path, FormatDetection::Extension).unwrap();
+        assert_eq!(detected.format, ImageFormat::Jpeg);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
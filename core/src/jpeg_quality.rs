@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::Path;
+
+const MARKER_DQT: u8 = 0xDB;
+const MARKER_SOS: u8 = 0xDA;
+const MARKER_EOI: u8 = 0xD9;
+
+// natural position of every coefficient, in the zigzag order DQT segments store them
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Annex K of the JPEG spec, scaled by libjpeg and most other encoders
+const ANNEX_K_LUMA: [u32; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const ANNEX_K_CHROMA: [u32; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+// mozjpeg's default for both luma and chroma, which ashuk's own jpgs use
+const MOZJPEG: [u32; 64] = [
+    16, 16, 16, 18, 25, 37, 56, 85, 16, 17, 20, 27, 34, 40, 53, 75, 16, 20, 24, 31, 43, 62, 91,
+    135, 18, 27, 31, 40, 53, 74, 106, 156, 25, 34, 43, 53, 69, 94, 131, 189, 37, 40, 62, 74, 94,
+    124, 169, 238, 56, 53, 91, 106, 131, 169, 226, 311, 85, 75, 135, 156, 189, 238, 311, 418,
+];
+
+const BASE_TABLES: [(&[u32; 64], &[u32; 64]); 2] =
+    [(&ANNEX_K_LUMA, &ANNEX_K_CHROMA), (&MOZJPEG, &MOZJPEG)];
+
+/// What to do when a jpg would be encoded to jpg above the quality it was saved at,
+/// which only adds bytes without bringing back lost detail.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum SourceQuality {
+    /// encode at the requested quality
+    #[default]
+    Ignore,
+    /// never encode above the estimated source quality
+    Clamp,
+    /// keep the source as it is, or clamp when it is cropped, resized or watermarked
+    Skip,
+}
+
+/// Marker and payload of every segment before the first scan, which holds the headers and tables.
+pub(crate) fn segments(contents: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    if !contents.starts_with(&[0xFF, 0xD8]) {
+        return segments;
+    }
+
+    let mut at = 2;
+    while at + 4 <= contents.len() && contents[at] == 0xFF {
+        let marker = contents[at + 1];
+        if marker == MARKER_SOS || marker == MARKER_EOI {
+            break;
+        }
+        let length = u16::from_be_bytes([contents[at + 2], contents[at + 3]]) as usize;
+        match contents.get(at + 4..at + 2 + length) {
+            Some(segment) => segments.push((marker, segment)),
+            None => break,
+        }
+        at += 2 + length;
+    }
+
+    segments
+}
+
+// quantization tables by slot, in natural order
+fn quant_tables(contents: &[u8]) -> [Option<[u32; 64]>; 4] {
+    let mut tables = [None; 4];
+    for (marker, segment) in segments(contents) {
+        if marker == MARKER_DQT {
+            read_dqt(segment, &mut tables);
+        }
+    }
+    tables
+}
+
+// a DQT segment holds one or more tables, each with its precision and slot in the first byte
+fn read_dqt(mut segment: &[u8], tables: &mut [Option<[u32; 64]>; 4]) {
+    while let Some((&info, rest)) = segment.split_first() {
+        let size = if info >> 4 == 0 { 1 } else { 2 };
+        let values = match rest.get(..64 * size) {
+            Some(values) => values,
+            None => return,
+        };
+
+        let mut table = [0; 64];
+        for (i, &natural) in ZIGZAG.iter().enumerate() {
+            table[natural] = match size {
+                1 => values[i] as u32,
+                _ => u16::from_be_bytes([values[2 * i], values[2 * i + 1]]) as u32,
+            };
+        }
+        if let Some(slot) = tables.get_mut((info & 0x0F) as usize) {
+            *slot = Some(table);
+        }
+        segment = &rest[64 * size..];
+    }
+}
+
+// how far `table` is from `base` scaled to `quality` like `jpeg_set_quality`
+fn distance(table: &[u32; 64], base: &[u32; 64], quality: u32) -> u32 {
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+
+    table
+        .iter()
+        .zip(base.iter())
+        .map(|(&value, &base)| {
+            let expected = ((base * scale + 50) / 100).clamp(1, 32767);
+            // baseline files cap every value at 255
+            let expected = if value <= 255 {
+                expected.min(255)
+            } else {
+                expected
+            };
+            (expected as i64 - value as i64).unsigned_abs() as u32
+        })
+        .sum()
+}
+
+/// Estimate the 1-100 quality a JPEG was saved at from its quantization tables.
+///
+/// Tables are compared to the libjpeg and mozjpeg defaults at every quality, so files from
+/// those encoders are matched exactly and others get the closest scale. `None` without tables.
+pub fn estimate(contents: &[u8]) -> Option<f32> {
+    let tables = quant_tables(contents);
+    let mut present = tables.iter().flatten();
+    let luma = present.next()?;
+    let chroma = present.next();
+
+    let mut best: Option<(u32, u32)> = None;
+    for (base_luma, base_chroma) in BASE_TABLES.iter() {
+        for quality in 1..=100 {
+            let error = distance(luma, base_luma, quality)
+                + chroma.map_or(0, |chroma| distance(chroma, base_chroma, quality));
+            if best.map_or(true, |(best_error, _)| error < best_error) {
+                best = Some((error, quality));
+            }
+        }
+    }
+
+    best.map(|(_, quality)| quality as f32)
+}
+
+/// Read `path` and estimate its quality, `None` when it isn't a JPEG.
+pub fn estimate_file<P: AsRef<Path>>(path: P) -> Option<f32> {
+    fs::read(path).ok().and_then(|contents| estimate(&contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::encode_jpeg;
+    use image::DynamicImage;
+
+    #[test]
+    fn test_estimate_quality() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }));
+        for quality in [35.0, 60.0, 92.0] {
+            let encoded = encode_jpeg(&image, quality, true).unwrap();
+            assert_eq!(estimate(&encoded), Some(quality));
+        }
+
+        // libjpeg's Annex K tables at quality 75, luma only like a grayscale file
+        let mut dqt = vec![0xFF, 0xD8, 0xFF, MARKER_DQT, 0, 67, 0];
+        let scaled = ANNEX_K_LUMA.map(|value| ((value * 50 + 50) / 100) as u8);
+        dqt.extend(ZIGZAG.iter().map(|&natural| scaled[natural]));
+        assert_eq!(estimate(&dqt), Some(75.0));
+
+        assert_eq!(estimate(b"not a jpeg"), None);
+    }
+}
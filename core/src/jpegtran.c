@@ -0,0 +1,216 @@
+/*
+ * Lossless JPEG transcoding for jpegtran.rs.
+ *
+ * libjpeg reports fatal errors by calling error_exit, which must not return. Neither a
+ * longjmp nor an unwind may cross Rust frames, so every libjpeg call of a transcode
+ * happens in this file, behind the usual setjmp, and errors come back as a status code.
+ */
+
+#include <setjmp.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "jpeglib.h"
+#include "jerror.h"
+#include "transupp.h"
+
+#define ASHUK_OK 0
+#define ASHUK_ERROR 1
+#define ASHUK_PARTIAL_EDGES 2
+
+#define ASHUK_MESSAGE_LENGTH 200
+#define INITIAL_OUTPUT_SIZE 65536
+
+/* a segment to copy into the output, its payload without the length bytes */
+struct ashuk_marker {
+  int code;
+  const unsigned char *data;
+  unsigned int length;
+};
+
+/* mirrored by `Transcode` in jpegtran.rs */
+struct ashuk_transcode {
+  const unsigned char *input;
+  unsigned long input_size;
+  const struct ashuk_marker *markers;
+  int marker_count;
+  int transform;
+  int perfect;
+  int trim;
+  int progressive;
+
+  unsigned char *output;
+  unsigned long output_size;
+  /* the image and its MCU size, for ASHUK_PARTIAL_EDGES */
+  unsigned int width;
+  unsigned int height;
+  unsigned int mcu_width;
+  unsigned int mcu_height;
+  char message[ASHUK_MESSAGE_LENGTH];
+};
+
+struct error_mgr {
+  struct jpeg_error_mgr pub;
+  jmp_buf jump;
+  char message[JMSG_LENGTH_MAX];
+};
+
+/* growable output buffer owned here, so it can be freed after an error */
+struct destination_mgr {
+  struct jpeg_destination_mgr pub;
+  unsigned char *buffer;
+  size_t size;
+};
+
+static void error_exit(j_common_ptr cinfo)
+{
+  struct error_mgr *err = (struct error_mgr *)cinfo->err;
+
+  (*cinfo->err->format_message)(cinfo, err->message);
+  longjmp(err->jump, 1);
+}
+
+/* a warning means corrupt data libjpeg papered over, fail instead like `jpegtran -strict` */
+static void emit_message(j_common_ptr cinfo, int msg_level)
+{
+  if (msg_level < 0)
+    (*cinfo->err->error_exit)(cinfo);
+}
+
+static void init_destination(j_compress_ptr cinfo)
+{
+  (void)cinfo;
+}
+
+static boolean empty_output_buffer(j_compress_ptr cinfo)
+{
+  struct destination_mgr *dest = (struct destination_mgr *)cinfo->dest;
+  size_t size = dest->size * 2;
+  unsigned char *buffer = (unsigned char *)realloc(dest->buffer, size);
+
+  if (buffer == NULL)
+    ERREXIT1(cinfo, JERR_OUT_OF_MEMORY, 10);
+
+  dest->pub.next_output_byte = buffer + dest->size;
+  dest->pub.free_in_buffer = size - dest->size;
+  dest->buffer = buffer;
+  dest->size = size;
+  return TRUE;
+}
+
+static void term_destination(j_compress_ptr cinfo)
+{
+  (void)cinfo;
+}
+
+static int is_marker(const struct ashuk_marker *marker, int code, const char *signature,
+                     unsigned int length)
+{
+  return marker->code == code && marker->length >= length &&
+         memcmp(marker->data, signature, length) == 0;
+}
+
+int ashuk_jpeg_transcode(struct ashuk_transcode *job)
+{
+  struct jpeg_decompress_struct source;
+  struct jpeg_compress_struct destination;
+  struct error_mgr err;
+  struct destination_mgr dest;
+  jpeg_transform_info info;
+  jvirt_barray_ptr *coefficients;
+  jvirt_barray_ptr *transformed;
+  /* read after a longjmp, so it must not live in a register */
+  volatile int status = ASHUK_ERROR;
+  int i;
+
+  memset(&source, 0, sizeof(source));
+  memset(&destination, 0, sizeof(destination));
+  memset(&info, 0, sizeof(info));
+  memset(&dest, 0, sizeof(dest));
+  job->output = NULL;
+  job->output_size = 0;
+  job->message[0] = '\0';
+
+  source.err = jpeg_std_error(&err.pub);
+  destination.err = &err.pub;
+  err.pub.error_exit = error_exit;
+  err.pub.emit_message = emit_message;
+
+  dest.buffer = (unsigned char *)malloc(INITIAL_OUTPUT_SIZE);
+  if (dest.buffer == NULL) {
+    strcpy(job->message, "out of memory");
+    return ASHUK_ERROR;
+  }
+  dest.size = INITIAL_OUTPUT_SIZE;
+  dest.pub.init_destination = init_destination;
+  dest.pub.empty_output_buffer = empty_output_buffer;
+  dest.pub.term_destination = term_destination;
+  dest.pub.next_output_byte = dest.buffer;
+  dest.pub.free_in_buffer = dest.size;
+
+  if (setjmp(err.jump)) {
+    strncpy(job->message, err.message, ASHUK_MESSAGE_LENGTH - 1);
+    job->message[ASHUK_MESSAGE_LENGTH - 1] = '\0';
+    goto done;
+  }
+
+  jpeg_create_decompress(&source);
+  jpeg_create_compress(&destination);
+  jpeg_mem_src(&source, job->input, job->input_size);
+  jpeg_read_header(&source, TRUE);
+
+  info.transform = (JXFORM_CODE)job->transform;
+  info.perfect = job->perfect ? TRUE : FALSE;
+  info.trim = job->trim ? TRUE : FALSE;
+  /* only fails when a perfect transform was asked for */
+  if (!jtransform_request_workspace(&source, &info)) {
+    job->width = source.image_width;
+    job->height = source.image_height;
+    job->mcu_width = source.num_components == 1 ? DCTSIZE : source.max_h_samp_factor * DCTSIZE;
+    job->mcu_height = source.num_components == 1 ? DCTSIZE : source.max_v_samp_factor * DCTSIZE;
+    status = ASHUK_PARTIAL_EDGES;
+    goto done;
+  }
+  coefficients = jpeg_read_coefficients(&source);
+
+  jpeg_copy_critical_parameters(&source, &destination);
+  /* the transformed blocks, or the source's own when nothing moves */
+  transformed = jtransform_adjust_parameters(&source, &destination, coefficients, &info);
+  destination.optimize_coding = TRUE;
+  if (job->progressive) {
+    jpeg_simple_progression(&destination);
+  } else {
+    /* mozjpeg defaults to a progressive scan script, dropping it gives a baseline file */
+    jpeg_c_set_bool_param(&destination, JBOOLEAN_OPTIMIZE_SCANS, FALSE);
+    destination.scan_info = NULL;
+    destination.num_scans = 0;
+  }
+  destination.dest = &dest.pub;
+  jpeg_write_coefficients(&destination, transformed);
+
+  for (i = 0; i < job->marker_count; i++) {
+    const struct ashuk_marker *marker = &job->markers[i];
+
+    /* the compressor writes its own JFIF and Adobe markers */
+    if ((destination.write_JFIF_header && is_marker(marker, JPEG_APP0, "JFIF", 5)) ||
+        (destination.write_Adobe_marker && is_marker(marker, JPEG_APP0 + 14, "Adobe", 5)))
+      continue;
+    jpeg_write_marker(&destination, marker->code, marker->data, marker->length);
+  }
+
+  jtransform_execute_transform(&source, &destination, coefficients, &info);
+  jpeg_finish_compress(&destination);
+  jpeg_finish_decompress(&source);
+
+  job->output = dest.buffer;
+  job->output_size = (unsigned long)(dest.size - dest.pub.free_in_buffer);
+  dest.buffer = NULL;
+  status = ASHUK_OK;
+
+done:
+  jpeg_destroy_compress(&destination);
+  jpeg_destroy_decompress(&source);
+  free(dest.buffer);
+  return status;
+}
@@ -0,0 +1,446 @@
+use mozjpeg_sys::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+use std::ptr;
+use std::result;
+use std::slice;
+
+use crate::compresser::OutputLocation;
+use crate::format_meta::FormatDetection;
+use crate::jpeg_quality;
+use crate::limits::Limits;
+
+const MARKER_APP0: u8 = 0xE0;
+const MARKER_APP1: u8 = 0xE1;
+const MARKER_APP2: u8 = 0xE2;
+const MARKER_APP15: u8 = 0xEF;
+const MARKER_COM: u8 = 0xFE;
+
+const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+const TAG_ORIENTATION: u16 = 0x0112;
+
+/// Metadata segments copied into a losslessly rewritten JPEG, like `jpegtran -copy`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum JpegMarkers {
+    /// strip every marker
+    None,
+    /// keep comments only
+    Comments,
+    /// keep the ICC colour profile only
+    Icc,
+    /// keep comments, EXIF, ICC and every other application marker
+    // the default, EXIF carries the orientation and dropping it would change how the image is shown
+    #[default]
+    All,
+}
+
+impl JpegMarkers {
+    fn copies(&self, marker: u8) -> bool {
+        match self {
+            JpegMarkers::None => false,
+            JpegMarkers::Comments => marker == MARKER_COM,
+            JpegMarkers::Icc => marker == MARKER_APP2,
+            JpegMarkers::All => {
+                (MARKER_APP0..=MARKER_APP15).contains(&marker) || marker == MARKER_COM
+            }
+        }
+    }
+}
+
+/// Rotation or flip applied to the DCT blocks of a JPEG, like `jpegtran -rotate` and `-flip`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum JpegTransform {
+    /// clockwise
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// mirror left to right
+    FlipHorizontal,
+    /// mirror top to bottom
+    FlipVertical,
+    /// mirror across the top-left to bottom-right diagonal
+    Transpose,
+    /// mirror across the top-right to bottom-left diagonal
+    Transverse,
+    /// undo the EXIF orientation and reset it to upright
+    #[default]
+    AutoOrient,
+}
+
+impl JpegTransform {
+    fn code(&self, orientation: Option<u16>) -> JXFORM_CODE {
+        match self {
+            JpegTransform::Rotate90 => JXFORM_CODE_JXFORM_ROT_90,
+            JpegTransform::Rotate180 => JXFORM_CODE_JXFORM_ROT_180,
+            JpegTransform::Rotate270 => JXFORM_CODE_JXFORM_ROT_270,
+            JpegTransform::FlipHorizontal => JXFORM_CODE_JXFORM_FLIP_H,
+            JpegTransform::FlipVertical => JXFORM_CODE_JXFORM_FLIP_V,
+            JpegTransform::Transpose => JXFORM_CODE_JXFORM_TRANSPOSE,
+            JpegTransform::Transverse => JXFORM_CODE_JXFORM_TRANSVERSE,
+            JpegTransform::AutoOrient => match orientation {
+                Some(2) => JXFORM_CODE_JXFORM_FLIP_H,
+                Some(3) => JXFORM_CODE_JXFORM_ROT_180,
+                Some(4) => JXFORM_CODE_JXFORM_FLIP_V,
+                Some(5) => JXFORM_CODE_JXFORM_TRANSPOSE,
+                Some(6) => JXFORM_CODE_JXFORM_ROT_90,
+                Some(7) => JXFORM_CODE_JXFORM_TRANSVERSE,
+                Some(8) => JXFORM_CODE_JXFORM_ROT_270,
+                _ => JXFORM_CODE_JXFORM_NONE,
+            },
+        }
+    }
+}
+
+/// What happens to the partial MCUs on the right and bottom edges, which can't be moved losslessly.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum PartialEdges {
+    /// drop them, losing less than one MCU of pixels on those edges, like `jpegtran -trim`
+    Trim,
+    /// fail unless every block can be moved, like `jpegtran -perfect`
+    #[default]
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct JpegTransformOptions {
+    pub transform: JpegTransform,
+    pub edges: PartialEdges,
+    /// `AutoOrient` resets the orientation of a copied EXIF segment, other transforms keep it
+    pub markers: JpegMarkers,
+    pub progressive: bool,
+    pub detection: FormatDetection,
+    pub output: OutputLocation,
+    /// appended to the file name, before the extension
+    pub suffix: Option<String>,
+    /// inputs over these are rejected before reading their blocks
+    pub limits: Limits,
+}
+
+impl Default for JpegTransformOptions {
+    fn default() -> Self {
+        Self {
+            transform: JpegTransform::default(),
+            edges: PartialEdges::default(),
+            markers: JpegMarkers::default(),
+            progressive: true,
+            detection: FormatDetection::default(),
+            output: OutputLocation::default(),
+            suffix: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransformError {
+    #[error("{width}×{height} is not a multiple of the {mcu_width}×{mcu_height} MCU, its partial edges can only be trimmed")]
+    PartialEdges {
+        width: u32,
+        height: u32,
+        mcu_width: u32,
+        mcu_height: u32,
+    },
+    #[error("{0}")]
+    Codec(String),
+}
+
+const STATUS_OK: c_int = 0;
+const STATUS_PARTIAL_EDGES: c_int = 2;
+const MESSAGE_LENGTH: usize = 200;
+
+// a segment to copy into the output, `struct ashuk_marker` in jpegtran.c
+#[repr(C)]
+struct Marker {
+    code: c_int,
+    data: *const u8,
+    length: c_uint,
+}
+
+// `struct ashuk_transcode` in jpegtran.c
+#[repr(C)]
+struct Transcode {
+    input: *const u8,
+    input_size: c_ulong,
+    markers: *const Marker,
+    marker_count: c_int,
+    transform: c_int,
+    perfect: c_int,
+    trim: c_int,
+    progressive: c_int,
+    output: *mut u8,
+    output_size: c_ulong,
+    width: c_uint,
+    height: c_uint,
+    mcu_width: c_uint,
+    mcu_height: c_uint,
+    message: [c_char; MESSAGE_LENGTH],
+}
+
+extern "C" {
+    // runs every libjpeg call behind a setjmp, so its fatal errors come back as a status
+    fn ashuk_jpeg_transcode(job: *mut Transcode) -> c_int;
+}
+
+// position of the orientation value in an EXIF segment, and whether it is big endian
+fn orientation_entry(exif: &[u8]) -> Option<(usize, bool)> {
+    if !exif.starts_with(EXIF_SIGNATURE) {
+        return None;
+    }
+    let tiff = &exif[EXIF_SIGNATURE.len()..];
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read = |at: usize, len: usize| {
+        let bytes = tiff.get(at..at + len)?;
+        let push = |value: u32, byte: &u8| value << 8 | *byte as u32;
+        Some(if big_endian {
+            bytes.iter().fold(0, push)
+        } else {
+            bytes.iter().rev().fold(0, push)
+        })
+    };
+
+    // the orientation lives in the first IFD, entries are 12 bytes with the value last
+    let ifd = read(4, 4)? as usize;
+    let count = read(ifd, 2)? as usize;
+    (0..count)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read(entry, 2) == Some(TAG_ORIENTATION as u32))
+        .map(|entry| (EXIF_SIGNATURE.len() + entry + 8, big_endian))
+}
+
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let (at, big_endian) = orientation_entry(exif)?;
+    let bytes = [*exif.get(at)?, *exif.get(at + 1)?];
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+
+fn set_exif_orientation(exif: &mut [u8], orientation: u16) {
+    if let Some((at, big_endian)) = orientation_entry(exif) {
+        let bytes = if big_endian {
+            orientation.to_be_bytes()
+        } else {
+            orientation.to_le_bytes()
+        };
+        if let Some(value) = exif.get_mut(at..at + 2) {
+            value.copy_from_slice(&bytes);
+        }
+    }
+}
+
+fn transcode(
+    contents: &[u8],
+    progressive: bool,
+    markers: JpegMarkers,
+    transform: Option<(JpegTransform, PartialEdges)>,
+) -> result::Result<Vec<u8>, TransformError> {
+    let segments = jpeg_quality::segments(contents);
+    let is_exif =
+        |marker: u8, data: &[u8]| marker == MARKER_APP1 && data.starts_with(EXIF_SIGNATURE);
+    let auto_orient = matches!(transform, Some((JpegTransform::AutoOrient, _)));
+
+    // the pixels are upright after an auto-orient, viewers must not turn them again
+    let mut exif = None;
+    let mut code = JXFORM_CODE_JXFORM_NONE;
+    if let Some((transform, _)) = transform {
+        let orientation = segments
+            .iter()
+            .filter(|(marker, data)| is_exif(*marker, data))
+            .find_map(|(_, data)| exif_orientation(data));
+        code = transform.code(orientation);
+        if auto_orient {
+            exif = segments
+                .iter()
+                .find(|(marker, data)| is_exif(*marker, data))
+                .map(|(_, data)| {
+                    let mut data = data.to_vec();
+                    set_exif_orientation(&mut data, 1);
+                    data
+                });
+        }
+    }
+
+    let copied = segments
+        .iter()
+        .filter(|(marker, _)| markers.copies(*marker))
+        .map(|&(marker, data)| {
+            let data = match &exif {
+                Some(exif) if is_exif(marker, data) => exif.as_slice(),
+                _ => data,
+            };
+            Marker {
+                code: marker as c_int,
+                data: data.as_ptr(),
+                length: data.len() as c_uint,
+            }
+        })
+        .collect::<Vec<Marker>>();
+
+    let edges = transform.map(|(_, edges)| edges);
+    let mut job = Transcode {
+        input: contents.as_ptr(),
+        input_size: contents.len() as c_ulong,
+        markers: copied.as_ptr(),
+        marker_count: copied.len() as c_int,
+        transform: code as c_int,
+        perfect: (edges == Some(PartialEdges::Error)) as c_int,
+        trim: (edges == Some(PartialEdges::Trim)) as c_int,
+        progressive: progressive as c_int,
+        output: ptr::null_mut(),
+        output_size: 0,
+        width: 0,
+        height: 0,
+        mcu_width: 0,
+        mcu_height: 0,
+        message: [0; MESSAGE_LENGTH],
+    };
+
+    // the markers and `contents` outlive the call, the output is ours to free
+    let status = unsafe { ashuk_jpeg_transcode(&mut job) };
+    match status {
+        STATUS_OK => unsafe {
+            let output = slice::from_raw_parts(job.output, job.output_size as usize).to_vec();
+            libc::free(job.output as *mut c_void);
+            Ok(output)
+        },
+        STATUS_PARTIAL_EDGES => Err(TransformError::PartialEdges {
+            width: job.width,
+            height: job.height,
+            mcu_width: job.mcu_width,
+            mcu_height: job.mcu_height,
+        }),
+        _ => {
+            let message = unsafe { CStr::from_ptr(job.message.as_ptr()) };
+            Err(TransformError::Codec(message.to_string_lossy().to_string()))
+        }
+    }
+}
+
+/// Rewrite a JPEG from its DCT coefficients with optimized Huffman tables, like `jpegtran -optimize`.
+///
+/// Decoded pixels are identical to the input's, only the entropy coding and the markers change.
+pub fn optimize(
+    contents: &[u8],
+    progressive: bool,
+    markers: JpegMarkers,
+) -> result::Result<Vec<u8>, String> {
+    transcode(contents, progressive, markers, None).map_err(|err| err.to_string())
+}
+
+/// Rotate or flip a JPEG by moving its DCT blocks, like `jpegtran -rotate`.
+///
+/// Nothing is decoded or quantized again, so the only loss is the edges `PartialEdges::Trim` drops.
+pub fn transform(
+    contents: &[u8],
+    options: &JpegTransformOptions,
+) -> result::Result<Vec<u8>, TransformError> {
+    transcode(
+        contents,
+        options.progressive,
+        options.markers,
+        Some((options.transform, options.edges)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::encode_jpeg;
+    use image::DynamicImage;
+
+    #[test]
+    fn test_optimize_keeps_pixels() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(120, 80, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 3) as u8, ((x * y) % 256) as u8])
+        }));
+        let original = encode_jpeg(&image, 90.0, false).unwrap();
+
+        let optimized = optimize(&original, true, JpegMarkers::None).unwrap();
+        let decode = |contents: &[u8]| image::load_from_memory(contents).unwrap().to_rgb8();
+        assert_eq!(decode(&original), decode(&optimized));
+
+        assert!(optimize(b"not a jpeg", true, JpegMarkers::All).is_err());
+        // rejected before libjpeg sees them: cut off, and lossless coding it isn't built for
+        assert!(optimize(&original[..original.len() / 2], true, JpegMarkers::All).is_err());
+        let at = original.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        let mut lossless = original.clone();
+        lossless[at + 1] = 0xC3;
+        assert!(optimize(&lossless, true, JpegMarkers::All).is_err());
+    }
+
+    #[test]
+    fn test_transform_blocks() {
+        let decode = |contents: &[u8]| image::load_from_memory(contents).unwrap();
+        let gradient = |width, height| {
+            let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+                image::Rgb([(x * 3) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+            }));
+            encode_jpeg(&image, 90.0, false).unwrap()
+        };
+        let transform_with = |contents: &[u8], transform, edges| {
+            let options = JpegTransformOptions {
+                transform,
+                edges,
+                ..Default::default()
+            };
+            super::transform(contents, &options)
+        };
+
+        // 4:2:0 chroma makes the MCU 16×16, these sides are multiples of it
+        let original = gradient(64, 48);
+        let rotated =
+            transform_with(&original, JpegTransform::Rotate90, PartialEdges::Error).unwrap();
+        let expected = decode(&original).rotate90().to_rgb8();
+        let rotated_pixels = decode(&rotated).to_rgb8();
+        assert_eq!(rotated_pixels.dimensions(), (48, 64));
+        // the inverse DCT rounds rows and columns in a different order once transposed
+        assert!(expected
+            .as_raw()
+            .iter()
+            .zip(rotated_pixels.as_raw())
+            .all(|(a, b)| (*a as i32 - *b as i32).abs() <= 2));
+        let back = transform_with(&rotated, JpegTransform::Rotate270, PartialEdges::Error).unwrap();
+        assert_eq!(decode(&back).to_rgb8(), decode(&original).to_rgb8());
+
+        let partial = gradient(70, 50);
+        assert!(matches!(
+            transform_with(&partial, JpegTransform::Rotate90, PartialEdges::Error),
+            Err(TransformError::PartialEdges {
+                mcu_width: 16,
+                mcu_height: 16,
+                ..
+            })
+        ));
+        let trimmed =
+            transform_with(&partial, JpegTransform::Rotate90, PartialEdges::Trim).unwrap();
+        assert_eq!(decode(&trimmed).to_rgb8().dimensions(), (48, 70));
+
+        // an EXIF segment saying the camera was turned clockwise, right after SOI
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(b"\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0");
+        assert_eq!(exif_orientation(&exif), Some(6));
+        let mut camera = original[..2].to_vec();
+        camera.extend_from_slice(&[0xFF, MARKER_APP1, 0, exif.len() as u8 + 2]);
+        camera.extend_from_slice(&exif);
+        camera.extend_from_slice(&original[2..]);
+
+        let upright =
+            transform_with(&camera, JpegTransform::AutoOrient, PartialEdges::Error).unwrap();
+        assert_eq!(decode(&upright).to_rgb8(), rotated_pixels);
+        let start = upright
+            .windows(EXIF_SIGNATURE.len())
+            .position(|window| window == EXIF_SIGNATURE)
+            .unwrap();
+        assert_eq!(exif_orientation(&upright[start..]), Some(1));
+    }
+}
@@ -1,2 +1,24 @@
+pub mod auto;
+pub mod batch;
+pub mod cache;
+pub mod classify;
 pub mod compresser;
+pub mod config;
+pub mod curve;
+pub mod diff;
+pub mod encoder;
 pub mod format_meta;
+pub mod jpeg_quality;
+pub mod jpegtran;
+pub mod limits;
+pub mod manifest;
+pub mod metrics;
+pub mod preview;
+pub mod report;
+pub mod rules;
+pub mod scheduler;
+pub mod size_budget;
+pub mod thumbnail;
+pub mod transform;
+pub mod watch;
+pub mod watermark;
@@ -0,0 +1,449 @@
+use image::io::Reader as ImageReader;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs;
+use std::io::Cursor;
+use std::result;
+
+use crate::compresser::{CompressError, Stage};
+use crate::format_meta::ImageFormat;
+use crate::jpeg_quality;
+
+/// Resource limits for decoding untrusted files, checked from the header before any pixel is decoded.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Limits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// width × height
+    pub max_pixels: Option<u64>,
+    /// bytes of decoded pixels, of every frame together
+    pub max_alloc: Option<u64>,
+    pub max_frames: Option<u32>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_width: None,
+            max_height: None,
+            // a 16K square
+            max_pixels: Some(16384 * 16384),
+            // the image crate's own default
+            max_alloc: Some(512 * 1024 * 1024),
+            max_frames: None,
+        }
+    }
+}
+
+/// The limit an input went over.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Width,
+    Height,
+    Pixels,
+    Alloc,
+    Frames,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = match self {
+            Limit::Width => "width",
+            Limit::Height => "height",
+            Limit::Pixels => "pixel count",
+            Limit::Alloc => "decoded size",
+            Limit::Frames => "frame count",
+        };
+        write!(f, "{}", limit)
+    }
+}
+
+/// What a file says about its pixels, read without decoding them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    /// at most, once decoded to 8 or 16 bits per channel
+    pub bytes_per_pixel: u32,
+    pub frames: u32,
+}
+
+impl Header {
+    /// Read the header of `contents`. `None` when it is truncated or malformed, which decoding reports.
+    pub fn parse(contents: &[u8], format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Jpeg => jpeg_header(contents),
+            ImageFormat::Png => png_header(contents),
+            ImageFormat::WebP => webp_header(contents),
+            ImageFormat::Gif => gif_header(contents),
+        }
+    }
+
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn alloc(&self) -> u64 {
+        self.pixels() * self.bytes_per_pixel as u64 * self.frames as u64
+    }
+}
+
+impl Limits {
+    /// No limits at all, for trusted inputs.
+    pub fn no_limits() -> Self {
+        Self {
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_alloc: None,
+            max_frames: None,
+        }
+    }
+
+    /// Reject `header` when it goes over any limit.
+    pub fn check(&self, path: &str, header: &Header) -> result::Result<(), CompressError> {
+        let checks = [
+            (
+                Limit::Width,
+                header.width as u64,
+                self.max_width.map(u64::from),
+            ),
+            (
+                Limit::Height,
+                header.height as u64,
+                self.max_height.map(u64::from),
+            ),
+            (Limit::Pixels, header.pixels(), self.max_pixels),
+            (
+                Limit::Frames,
+                header.frames as u64,
+                self.max_frames.map(u64::from),
+            ),
+            (Limit::Alloc, header.alloc(), self.max_alloc),
+        ];
+
+        for (limit, actual, max) in checks {
+            match max {
+                Some(max) if actual > max => {
+                    return Err(CompressError::LimitExceeded {
+                        path: path.to_string(),
+                        limit,
+                        actual,
+                        max,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the header of already read `contents`.
+    ///
+    /// Headers this module can't parse are measured by the image crate instead,
+    /// and contents neither can read are a decode error.
+    pub fn check_contents(
+        &self,
+        path: &str,
+        contents: &[u8],
+        format: ImageFormat,
+    ) -> result::Result<(), CompressError> {
+        if let Some(header) = Header::parse(contents, format) {
+            return self.check(path, &header);
+        }
+
+        let (width, height) =
+            ImageReader::with_format(Cursor::new(contents), format.to_image_format())
+                .into_dimensions()
+                .map_err(|source| CompressError::Decode {
+                    path: path.to_string(),
+                    source,
+                })?;
+        // rgba8, frames are counted while decoding
+        let header = Header {
+            width,
+            height,
+            bytes_per_pixel: 4,
+            frames: 1,
+        };
+        self.check(path, &header)
+    }
+
+    /// Read `path` and check its header.
+    pub fn check_file(&self, path: &str, format: ImageFormat) -> result::Result<(), CompressError> {
+        let contents = fs::read(path).map_err(CompressError::io(path, Stage::Read))?;
+        self.check_contents(path, &contents, format)
+    }
+
+    /// The same limits for the image crate, which enforces them again while decoding.
+    ///
+    /// It has no pixel or frame limit, those are checked from the header and, for animations, per decoded frame.
+    pub(crate) fn image_limits(&self) -> image::io::Limits {
+        let mut limits = image::io::Limits::no_limits();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = self.max_alloc;
+        limits
+    }
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]) as u32)
+}
+
+fn le16(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes([*data.get(at)?, *data.get(at + 1)?]) as u32)
+}
+
+fn le24(data: &[u8], at: usize) -> Option<u32> {
+    Some(le16(data, at)? | (*data.get(at + 2)? as u32) << 16)
+}
+
+fn le32(data: &[u8], at: usize) -> Option<u32> {
+    Some(le16(data, at)? | le16(data, at + 2)? << 16)
+}
+
+fn jpeg_header(contents: &[u8]) -> Option<Header> {
+    // start of frame, every SOFn except DHT, JPG and DAC which share the range
+    let (_, frame) = jpeg_quality::segments(contents)
+        .into_iter()
+        .find(|(marker, _)| {
+            (0xC0..=0xCF).contains(marker) && ![0xC4, 0xC8, 0xCC].contains(marker)
+        })?;
+
+    Some(Header {
+        height: be16(frame, 1)?,
+        width: be16(frame, 3)?,
+        bytes_per_pixel: *frame.get(5)? as u32,
+        frames: 1,
+    })
+}
+
+fn png_header(contents: &[u8]) -> Option<Header> {
+    // IHDR is always the first chunk, right after the signature
+    if !contents.starts_with(b"\x89PNG\r\n\x1a\n") || contents.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(contents.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(contents.get(20..24)?.try_into().ok()?);
+    let channels = match contents.get(25)? {
+        0 => 1,
+        4 => 2,
+        2 => 3,
+        // palettes expand to rgb, or rgba with transparency
+        _ => 4,
+    };
+    let depth = if *contents.get(24)? == 16 { 2 } else { 1 };
+
+    Some(Header {
+        width,
+        height,
+        bytes_per_pixel: channels * depth,
+        frames: 1,
+    })
+}
+
+fn webp_header(contents: &[u8]) -> Option<Header> {
+    if contents.get(..4)? != b"RIFF" || contents.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let mut header: Option<Header> = None;
+    let mut at = 12;
+    while let (Some(fourcc), Some(size)) = (contents.get(at..at + 4), le32(contents, at + 4)) {
+        let data = contents.get(at + 8..)?;
+        match fourcc {
+            b"VP8 " if header.is_none() => {
+                return Some(Header {
+                    width: le16(data, 6)? & 0x3FFF,
+                    height: le16(data, 8)? & 0x3FFF,
+                    bytes_per_pixel: 3,
+                    frames: 1,
+                })
+            }
+            b"VP8L" if header.is_none() => {
+                let bits = le32(data, 1)?;
+                return Some(Header {
+                    width: (bits & 0x3FFF) + 1,
+                    height: (bits >> 14 & 0x3FFF) + 1,
+                    bytes_per_pixel: 4,
+                    frames: 1,
+                });
+            }
+            b"VP8X" => {
+                // the canvas of an extended file, frames are counted below
+                header = Some(Header {
+                    width: le24(data, 4)? + 1,
+                    height: le24(data, 7)? + 1,
+                    bytes_per_pixel: 4,
+                    frames: 0,
+                });
+            }
+            b"ANMF" => {
+                if let Some(header) = header.as_mut() {
+                    header.frames += 1;
+                }
+            }
+            _ => {}
+        }
+        // chunks are padded to an even size
+        at += 8 + size as usize + (size & 1) as usize;
+    }
+
+    header.map(|header| Header {
+        frames: header.frames.max(1),
+        ..header
+    })
+}
+
+fn gif_header(contents: &[u8]) -> Option<Header> {
+    if !contents.starts_with(b"GIF87a") && !contents.starts_with(b"GIF89a") {
+        return None;
+    }
+    let color_table = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 0x07) + 1)
+        } else {
+            0
+        }
+    };
+    // data sub-blocks, each prefixed with its length and ended by an empty one
+    let skip_blocks = |mut at: usize| loop {
+        let length = *contents.get(at)? as usize;
+        at += 1 + length;
+        if length == 0 {
+            return Some(at);
+        }
+    };
+
+    let width = le16(contents, 6)?;
+    let height = le16(contents, 8)?;
+    let mut frames = 0;
+    let mut at = 13 + color_table(*contents.get(10)?);
+    // a truncated file keeps the frames counted so far
+    loop {
+        let next = match contents.get(at) {
+            // extension: introducer, label, blocks
+            Some(0x21) => skip_blocks(at + 2),
+            // image descriptor, local color table, LZW code size, blocks
+            Some(0x2C) => {
+                frames += 1;
+                contents
+                    .get(at + 9)
+                    .and_then(|&flags| skip_blocks(at + 10 + color_table(flags) + 1))
+            }
+            _ => None,
+        };
+        match next {
+            Some(next) => at = next,
+            None => break,
+        }
+    }
+
+    Some(Header {
+        width,
+        height,
+        // every frame is composited onto an rgba canvas
+        bytes_per_pixel: 4,
+        frames: frames.max(1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{encode_jpeg, encode_webp_animation};
+    use image::codecs::gif::GifEncoder;
+    use image::{DynamicImage, Frame, RgbaImage};
+
+    #[test]
+    fn test_read_headers() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(30, 20, image::Rgba([9; 4])));
+        let header = |contents: &[u8], format| Header::parse(contents, format).unwrap();
+
+        let jpeg = encode_jpeg(&image, 80.0, true).unwrap();
+        assert_eq!(header(&jpeg, ImageFormat::Jpeg).width, 30);
+        assert_eq!(header(&jpeg, ImageFormat::Jpeg).bytes_per_pixel, 3);
+
+        let mut png = Vec::new();
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        assert_eq!(
+            header(&png, ImageFormat::Png),
+            Header {
+                width: 30,
+                height: 20,
+                bytes_per_pixel: 4,
+                frames: 1
+            }
+        );
+
+        // identical frames would be merged by the webp encoder
+        let frames = (0..3)
+            .map(|i| {
+                Frame::new(RgbaImage::from_pixel(
+                    30,
+                    20,
+                    image::Rgba([i * 80, 0, 0, 255]),
+                ))
+            })
+            .collect::<Vec<Frame>>();
+        let mut gif = Vec::new();
+        GifEncoder::new(&mut gif)
+            .encode_frames(frames.clone())
+            .unwrap();
+        assert_eq!(header(&gif, ImageFormat::Gif).frames, 3);
+
+        let webp = encode_webp_animation(&frames, 75.0, false).unwrap();
+        let webp = header(&webp, ImageFormat::WebP);
+        assert_eq!((webp.width, webp.height, webp.frames), (30, 20, 3));
+    }
+
+    #[test]
+    fn test_reject_over_limits() {
+        // a 60000×60000 png header, nothing after it is needed
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&60000u32.to_be_bytes());
+        png.extend_from_slice(&60000u32.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let err = Limits::default()
+            .check_contents("bomb.png", &png, ImageFormat::Png)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            CompressError::LimitExceeded {
+                limit: Limit::Pixels,
+                actual: 3_600_000_000,
+                ..
+            }
+        ));
+
+        let limits = Limits {
+            max_width: Some(1000),
+            ..Limits::no_limits()
+        };
+        assert!(matches!(
+            limits.check_contents("bomb.png", &png, ImageFormat::Png),
+            Err(CompressError::LimitExceeded {
+                limit: Limit::Width,
+                ..
+            })
+        ));
+        assert!(Limits::no_limits()
+            .check_contents("bomb.png", &png, ImageFormat::Png)
+            .is_ok());
+
+        // a header that can't be read is not waved through
+        assert!(matches!(
+            Limits::default().check_contents("short.png", &png[..20], ImageFormat::Png),
+            Err(CompressError::Decode { .. })
+        ));
+    }
+}
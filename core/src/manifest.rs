@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cache::hash_file;
+use crate::compresser::Result;
+use crate::format_meta::ImageFormat;
+
+/// One written file of an input, as consumed by build tooling.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ManifestVariant {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    pub bytes: u64,
+    /// blake3 hash of the file contents
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub input: String,
+    pub variants: Vec<ManifestVariant>,
+}
+
+/// How `<picture>` snippets refer to the variants.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct PictureOptions {
+    /// prepended to the file name of each variant instead of using its path, e.g. `/assets/`
+    pub url_prefix: Option<String>,
+    /// `sizes` attribute of the sources
+    pub sizes: Option<String>,
+    #[serde(default)]
+    pub alt: String,
+}
+
+impl ManifestEntry {
+    /// Entry for the outputs of `result`, hashing each written file.
+    pub fn from_result(input: &str, result: &Result) -> io::Result<Self> {
+        let variants = result
+            .outputs
+            .iter()
+            .map(|output| {
+                Ok(ManifestVariant {
+                    path: output.path.clone(),
+                    width: output.width,
+                    height: output.height,
+                    format: output.format,
+                    bytes: output.size,
+                    hash: hash_file(&output.path)?,
+                })
+            })
+            .collect::<io::Result<Vec<ManifestVariant>>>()?;
+
+        Ok(Self {
+            input: input.to_string(),
+            variants,
+        })
+    }
+
+    /// `<picture>` markup with one `<source>` per modern format and an `<img>` fallback.
+    pub fn picture(&self, options: &PictureOptions) -> String {
+        let fallback = [
+            ImageFormat::Jpeg,
+            ImageFormat::Png,
+            ImageFormat::WebP,
+            ImageFormat::Gif,
+        ]
+        .iter()
+        .copied()
+        .find(|format| {
+            self.variants
+                .iter()
+                .any(|variant| variant.format == *format)
+        });
+        let fallback = match fallback {
+            Some(format) => format,
+            None => return String::new(),
+        };
+
+        let mut html = String::from("<picture>\n");
+        for format in [ImageFormat::WebP, ImageFormat::Png, ImageFormat::Jpeg] {
+            if format == fallback || !self.variants.iter().any(|v| v.format == format) {
+                continue;
+            }
+            html += &format!(
+                "  <source type=\"{}\" srcset=\"{}\"{}>\n",
+                format.mime_type(),
+                escape(&self.srcset(format, options)),
+                sizes_attribute(options)
+            );
+        }
+
+        // the widest fallback is the src for browsers without srcset support
+        let largest = self
+            .variants
+            .iter()
+            .filter(|variant| variant.format == fallback)
+            .max_by_key(|variant| variant.width);
+        if let Some(largest) = largest {
+            html += &format!(
+                "  <img src=\"{}\" srcset=\"{}\"{} width=\"{}\" height=\"{}\" alt=\"{}\">\n",
+                escape(&url(&largest.path, options)),
+                escape(&self.srcset(fallback, options)),
+                sizes_attribute(options),
+                largest.width,
+                largest.height,
+                escape(&options.alt)
+            );
+        }
+        html += "</picture>\n";
+
+        html
+    }
+
+    fn srcset(&self, format: ImageFormat, options: &PictureOptions) -> String {
+        let mut variants = self
+            .variants
+            .iter()
+            .filter(|variant| variant.format == format)
+            .collect::<Vec<&ManifestVariant>>();
+        variants.sort_by_key(|variant| variant.width);
+
+        variants
+            .iter()
+            .map(|variant| format!("{} {}w", url(&variant.path, options), variant.width))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+fn url(path: &str, options: &PictureOptions) -> String {
+    match &options.url_prefix {
+        Some(prefix) => {
+            let file_name = Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            prefix.to_string() + &file_name
+        }
+        None => path.replace('\\', "/"),
+    }
+}
+
+fn sizes_attribute(options: &PictureOptions) -> String {
+    options
+        .sizes
+        .as_ref()
+        .map(|sizes| format!(" sizes=\"{}\"", escape(sizes)))
+        .unwrap_or_default()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn push(&mut self, entry: ManifestEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let contents = serde_json::to_vec_pretty(self)?;
+        fs::write(path, contents)
+    }
+
+    /// Snippets of every entry, each preceded by a comment naming its input.
+    pub fn pictures(&self, options: &PictureOptions) -> String {
+        self.entries
+            .iter()
+            .map(|entry| format!("<!-- {} -->\n{}", entry.input, entry.picture(options)))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(path: &str, width: u32, format: ImageFormat) -> ManifestVariant {
+        ManifestVariant {
+            path: path.to_string(),
+            width,
+            height: width / 2,
+            format,
+            bytes: 100,
+            hash: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_picture() {
+        let entry = ManifestEntry {
+            input: "hero.png".to_string(),
+            variants: vec![
+                variant("dist/hero-640w.webp", 640, ImageFormat::WebP),
+                variant("dist/hero-320w.webp", 320, ImageFormat::WebP),
+                variant("dist/hero-320w.jpg", 320, ImageFormat::Jpeg),
+                variant("dist/hero-640w.jpg", 640, ImageFormat::Jpeg),
+            ],
+        };
+        let options = PictureOptions {
+            url_prefix: Some("/img/".to_string()),
+            sizes: Some("100vw".to_string()),
+            alt: "A \"hero\"".to_string(),
+        };
+
+        assert_eq!(
+            entry.picture(&options),
+            "<picture>\n\
+             \x20 <source type=\"image/webp\" srcset=\"/img/hero-320w.webp 320w, /img/hero-640w.webp 640w\" sizes=\"100vw\">\n\
+             \x20 <img src=\"/img/hero-640w.jpg\" srcset=\"/img/hero-320w.jpg 320w, /img/hero-640w.jpg 640w\" sizes=\"100vw\" width=\"640\" height=\"320\" alt=\"A &quot;hero&quot;\">\n\
+             </picture>\n"
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use image::{RgbImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// PSNR reported for identical images, instead of infinity which JSON can't represent.
+pub const MAX_PSNR: f64 = 100.0;
+
+/// Pixel error between two images of the same size.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct ErrorStats {
+    /// mean squared error over all channels
+    pub mse: f64,
+    /// peak signal-to-noise ratio in dB, higher is better
+    pub psnr: f64,
+    /// mean absolute error over all channels, 0-255
+    pub mean_error: f64,
+    /// largest channel difference, 0-255
+    pub max_error: u8,
+}
+
+pub fn psnr(mse: f64) -> f64 {
+    if mse <= 0.0 {
+        MAX_PSNR
+    } else {
+        (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+    }
+}
+
+/// Error of the `width` × `height` region at `x`, `y`. Both images must be the same size.
+pub fn error_stats(
+    original: &RgbImage,
+    compressed: &RgbImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> ErrorStats {
+    let (mut squared, mut absolute, mut max_error) = (0.0, 0.0, 0);
+
+    for py in y..y + height {
+        for px in x..x + width {
+            let a = original.get_pixel(px, py).0;
+            let b = compressed.get_pixel(px, py).0;
+            for channel in 0..3 {
+                let difference = (a[channel] as i32 - b[channel] as i32).unsigned_abs();
+                squared += (difference * difference) as f64;
+                absolute += difference as f64;
+                max_error = max_error.max(difference as u8);
+            }
+        }
+    }
+
+    let samples = (width as f64 * height as f64 * 3.0).max(1.0);
+    let mse = squared / samples;
+    ErrorStats {
+        mse,
+        psnr: psnr(mse),
+        mean_error: absolute / samples,
+        max_error,
+    }
+}
+
+// SSIM window size and the distance between windows
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+
+/// Structural similarity of the luma of two same-size images, 1 for identical images.
+pub fn ssim(original: &RgbImage, compressed: &RgbImage) -> f64 {
+    let luma = |image: &RgbImage| {
+        image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                luma(r, g, b)
+            })
+            .collect::<Vec<f64>>()
+    };
+    let (width, height) = original.dimensions();
+
+    windowed_ssim(
+        &luma(original),
+        &luma(compressed),
+        width,
+        height,
+        |_, _, _, _| true,
+    )
+}
+
+/// Like `ssim`, on colours weighted by alpha and leaving out windows fully transparent in `original`,
+/// since encoders are free to change what can't be seen.
+pub fn ssim_with_alpha(original: &RgbaImage, compressed: &RgbaImage) -> f64 {
+    let luma = |image: &RgbaImage| {
+        image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b, a] = pixel.0;
+                luma(r, g, b) * a as f64 / 255.0
+            })
+            .collect::<Vec<f64>>()
+    };
+    let (width, height) = original.dimensions();
+    let visible = |x, y, window_width, window_height| {
+        (y..y + window_height)
+            .any(|py| (x..x + window_width).any(|px| original.get_pixel(px, py).0[3] > 0))
+    };
+
+    windowed_ssim(&luma(original), &luma(compressed), width, height, visible)
+}
+
+fn luma(r: u8, g: u8, b: u8) -> f64 {
+    0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+}
+
+// mean SSIM of the windows `include` accepts, 1 when there are none
+fn windowed_ssim<F>(a: &[f64], b: &[f64], width: u32, height: u32, include: F) -> f64
+where
+    F: Fn(u32, u32, u32, u32) -> bool,
+{
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    // images smaller than a window are compared as a single window
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    if window_width == 0 || window_height == 0 {
+        return 1.0;
+    }
+
+    let (mut total, mut windows) = (0.0, 0);
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            if !include(x, y, window_width, window_height) {
+                x += SSIM_STEP;
+                continue;
+            }
+
+            let count = (window_width * window_height) as f64;
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+            for py in y..y + window_height {
+                for px in x..x + window_width {
+                    let i = (py * width + px) as usize;
+                    sum_a += a[i];
+                    sum_b += b[i];
+                    sum_aa += a[i] * a[i];
+                    sum_bb += b[i] * b[i];
+                    sum_ab += a[i] * b[i];
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+            let variance_a = sum_aa / count - mean_a * mean_a;
+            let variance_b = sum_bb / count - mean_b * mean_b;
+            let covariance = sum_ab / count - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            windows += 1;
+
+            x += SSIM_STEP;
+        }
+        y += SSIM_STEP;
+    }
+
+    if windows == 0 {
+        1.0
+    } else {
+        total / windows as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_error_stats() {
+        let original = RgbImage::from_pixel(4, 4, image::Rgb([100, 100, 100]));
+        let mut compressed = original.clone();
+        assert_eq!(
+            error_stats(&original, &compressed, 0, 0, 4, 4).psnr,
+            MAX_PSNR
+        );
+
+        compressed.put_pixel(0, 0, image::Rgb([110, 100, 100]));
+        let stats = error_stats(&original, &compressed, 0, 0, 4, 4);
+        assert_eq!(stats.max_error, 10);
+        assert!((stats.mse - 100.0 / 48.0).abs() < 1e-9);
+        assert_eq!(error_stats(&original, &compressed, 2, 2, 2, 2).mse, 0.0);
+    }
+
+    #[test]
+    fn test_ssim() {
+        let original =
+            RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 8) as u8, (y * 8) as u8, 0]));
+        assert!((ssim(&original, &original) - 1.0).abs() < 1e-9);
+
+        let noisy = RgbImage::from_fn(32, 32, |x, y| {
+            let pixel = original.get_pixel(x, y).0;
+            let noise = if (x + y) % 2 == 0 { 30 } else { 0 };
+            image::Rgb([pixel[0].saturating_add(noise), pixel[1], pixel[2]])
+        });
+        let score = ssim(&original, &noisy);
+        assert!(score < 0.99 && score > 0.0, "ssim {}", score);
+    }
+}
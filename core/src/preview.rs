@@ -0,0 +1,309 @@
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use std::result;
+
+use crate::auto::AUTO_EXTENSION;
+use crate::compresser::{self, CompressError, CompressOptions};
+use crate::curve::{self, CurveOptions, QualityCurve};
+use crate::encoder;
+use crate::format_meta::{FormatDetection, ImageFormat};
+use crate::limits::Limits;
+use crate::metrics::{self, ErrorStats};
+use crate::rules;
+use crate::transform::Crop;
+
+// stands in for a file path in errors
+const PREVIEW_PATH: &str = "preview";
+
+/// Part of the output to inspect at 1:1, in output pixels.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An encode that only lives in memory.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// encoded output, or a lossless PNG of the inspected region of it
+    pub bytes: Vec<u8>,
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// size the output would have on disk
+    pub predicted_size: u64,
+    /// error of the whole output against the transformed source
+    pub metrics: ErrorStats,
+    /// the inspected region, clamped to the output
+    pub region: Option<Region>,
+    /// error inside the inspected region
+    pub region_metrics: Option<ErrorStats>,
+}
+
+impl Preview {
+    pub fn mime_type(&self) -> &'static str {
+        self.format.mime_type()
+    }
+
+    pub fn data_url(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.mime_type(),
+            base64::encode(&self.bytes)
+        )
+    }
+}
+
+/// A decoded input, kept so a UI only re-encodes while the options change.
+pub struct Source {
+    pub path: String,
+    pub format: ImageFormat,
+    pub image: DynamicImage,
+}
+
+impl Source {
+    /// Decode `file_path` within `limits`. The file is only read.
+    pub fn open(
+        file_path: &str,
+        detection: FormatDetection,
+        limits: &Limits,
+    ) -> result::Result<Self, CompressError> {
+        let detected = compresser::detect(file_path, detection)?;
+        let image = compresser::decode(file_path, detected.format, limits)?;
+
+        Ok(Self {
+            path: file_path.to_string(),
+            format: detected.format,
+            image,
+        })
+    }
+
+    /// Preview with `options`, after applying its conversion rules to this file.
+    ///
+    /// The output is encoded the way compressing the file would, lossless jpeg and the source quality included.
+    /// Variants write several files and an auto target has no single format, both are rejected.
+    pub fn preview(
+        &self,
+        options: &CompressOptions,
+        region: Option<Region>,
+    ) -> result::Result<Preview, CompressError> {
+        let mut options = rules::route(options, self.path.as_ref());
+        if options.variants.is_some() {
+            return Err(CompressError::Unestimable {
+                path: self.path.clone(),
+                option: "variants",
+            });
+        }
+        let format = target_format(&self.path, self.format, &options)?;
+
+        let prepared = compresser::prepare(self.image.clone(), &options)?;
+        let encoded = compresser::encode_file(
+            &self.path,
+            self.format,
+            format,
+            &mut options,
+            Some(&prepared),
+            &mut Vec::new(),
+        )?;
+        finish(&prepared, encoded.bytes, format, region)
+    }
+
+    /// Sizes and SSIM across the quality range of the target format of `options`.
+    pub fn quality_curve(
+        &self,
+        options: &CompressOptions,
+        curve: &CurveOptions,
+    ) -> result::Result<QualityCurve, CompressError> {
+        let options = rules::route(options, self.path.as_ref());
+        let format = target_format(&self.path, self.format, &options)?;
+
+        let prepared = compresser::prepare(self.image.clone(), &options)?;
+        curve::quality_curve(&prepared, format, &options, curve)
+    }
+}
+
+/// Decode `file_path` and preview it with `options`. The file is only read.
+pub fn preview(
+    file_path: &str,
+    options: &CompressOptions,
+    region: Option<Region>,
+) -> result::Result<Preview, CompressError> {
+    Source::open(file_path, options.detection, &options.limits)?.preview(options, region)
+}
+
+// the format `options` encodes a `source` image to
+fn target_format(
+    path: &str,
+    source: ImageFormat,
+    options: &CompressOptions,
+) -> result::Result<ImageFormat, CompressError> {
+    let extension = options.target_extension(source);
+    // the winner is only known after encoding every candidate, so there's no single format to preview
+    if extension == AUTO_EXTENSION {
+        return Err(CompressError::Unestimable {
+            path: path.to_string(),
+            option: AUTO_EXTENSION,
+        });
+    }
+    let format = ImageFormat::from_extension(&extension)
+        .ok_or(CompressError::UnsupportedExtension(extension))?;
+    if !source.can_compress(&format) {
+        return Err(CompressError::Unsupported(source, format));
+    }
+    Ok(format)
+}
+
+/// Preview an already decoded image.
+///
+/// Without the file, settings that start from its bytes, like lossless jpeg or the source quality, are not applied.
+pub fn preview_image(
+    source: &DynamicImage,
+    source_format: ImageFormat,
+    options: &CompressOptions,
+    region: Option<Region>,
+) -> result::Result<Preview, CompressError> {
+    let format = target_format(PREVIEW_PATH, source_format, options)?;
+
+    let prepared = compresser::prepare(source.clone(), options)?;
+    let encoded = encoder::encode(&prepared, format, options, PREVIEW_PATH)?;
+    finish(&prepared, encoded, format, region)
+}
+
+// the preview of `encoded`, measured against the `prepared` source
+fn finish(
+    prepared: &DynamicImage,
+    encoded: Vec<u8>,
+    format: ImageFormat,
+    region: Option<Region>,
+) -> result::Result<Preview, CompressError> {
+    let decoded = image::load_from_memory_with_format(&encoded, format.to_image_format()).map_err(
+        |source| CompressError::Decode {
+            path: PREVIEW_PATH.to_string(),
+            source,
+        },
+    )?;
+
+    let (width, height) = decoded.dimensions();
+    let (expected, actual) = (prepared.to_rgb8(), decoded.to_rgb8());
+    let metrics = metrics::error_stats(&expected, &actual, 0, 0, width, height);
+    let predicted_size = encoded.len() as u64;
+
+    let region = region.map(|region| {
+        let (x, y, width, height) = Crop::Rect {
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        }
+        .region(&decoded);
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    });
+
+    let (bytes, format, region_metrics) = match region {
+        Some(region) => {
+            let view = decoded.crop_imm(region.x, region.y, region.width, region.height);
+            // lossless, so the region shows exactly the artifacts of the real encode
+            let png = encoder::encode_png(&view, 0)
+                .map_err(CompressError::codec(PREVIEW_PATH, ImageFormat::Png))?;
+            let stats = metrics::error_stats(
+                &expected,
+                &actual,
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+            );
+            (png, ImageFormat::Png, Some(stats))
+        }
+        None => (encoded, format, None),
+    };
+
+    Ok(Preview {
+        bytes,
+        format,
+        width,
+        height,
+        predicted_size,
+        metrics,
+        region,
+        region_metrics,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_preview_region() {
+        let source = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+        }));
+        let options = CompressOptions {
+            quality: Some(50.0),
+            extension: "jpg".to_string(),
+            ..Default::default()
+        };
+
+        let full = preview_image(&source, ImageFormat::Jpeg, &options, None).unwrap();
+        assert_eq!(full.format, ImageFormat::Jpeg);
+        assert_eq!(full.predicted_size, full.bytes.len() as u64);
+        assert!(full.data_url().starts_with("data:image/jpeg;base64,"));
+
+        let region = Region {
+            x: 48,
+            y: 40,
+            width: 32,
+            height: 32,
+        };
+        let zoomed = preview_image(&source, ImageFormat::Jpeg, &options, Some(region)).unwrap();
+        assert_eq!(zoomed.format, ImageFormat::Png);
+        assert_eq!(zoomed.predicted_size, full.predicted_size);
+        assert_eq!(
+            zoomed.region,
+            Some(Region {
+                x: 48,
+                y: 40,
+                width: 16,
+                height: 8
+            })
+        );
+        let view = image::load_from_memory(&zoomed.bytes).unwrap();
+        assert_eq!((view.width(), view.height()), (16, 8));
+    }
+
+    #[test]
+    fn test_preview_lossless_jpeg() {
+        let dir = std::env::temp_dir().join("ashuk-preview-lossless");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.jpg");
+        image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+        })
+        .save(&input)
+        .unwrap();
+
+        let mut options = CompressOptions {
+            extension: "jpg".to_string(),
+            ..Default::default()
+        };
+        options.encoder.jpeg.lossless = true;
+        let preview = preview(&input.to_string_lossy(), &options, None).unwrap();
+
+        let optimized = crate::jpegtran::optimize(
+            &std::fs::read(&input).unwrap(),
+            options.encoder.jpeg.progressive,
+            options.encoder.jpeg.markers,
+        )
+        .unwrap();
+        assert_eq!(preview.predicted_size, optimized.len() as u64);
+        assert_eq!(preview.metrics.max_error, 0);
+    }
+}
@@ -0,0 +1,364 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::batch::BatchItem;
+use crate::compresser::{Result, Status};
+use crate::format_meta::{FormatDetection, ImageFormat};
+use crate::jpeg_quality;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    /// one JSON object per line, written as soon as each file is done
+    Ndjson,
+    /// a single document with every entry and a summary, written at the end
+    Json,
+    Csv,
+}
+
+impl ReportFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+
+        Some(match ext.as_str() {
+            "ndjson" | "jsonl" => ReportFormat::Ndjson,
+            "json" => ReportFormat::Json,
+            "csv" => ReportFormat::Csv,
+            _ => return None,
+        })
+    }
+}
+
+/// Input side of an entry, which has to be read before compressing since inputs may be overwritten.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InputInfo {
+    pub path: String,
+    pub size: u64,
+    pub format: Option<ImageFormat>,
+    /// estimated quality the input was saved at, for jpg only
+    #[serde(default)]
+    pub quality: Option<f32>,
+}
+
+impl InputInfo {
+    pub fn read<P: AsRef<Path>>(path: P, detection: FormatDetection) -> Self {
+        let path = path.as_ref();
+        let format = ImageFormat::detect(path, detection)
+            .ok()
+            .map(|detected| detected.format);
+
+        Self {
+            path: path.to_string_lossy().to_string(),
+            size: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            quality: match format {
+                Some(ImageFormat::Jpeg) => jpeg_quality::estimate_file(path),
+                _ => None,
+            },
+            format,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReportEntry {
+    pub input_path: String,
+    pub input_size: u64,
+    pub input_format: Option<ImageFormat>,
+    /// estimated quality the input was saved at, for jpg only
+    #[serde(default)]
+    pub input_quality: Option<f32>,
+    pub output_path: Option<String>,
+    pub output_size: Option<u64>,
+    pub output_format: Option<ImageFormat>,
+    /// output size divided by input size
+    pub ratio: Option<f64>,
+    pub elapsed_ms: Option<u64>,
+    pub status: Status,
+    pub cached: bool,
+    pub error: Option<String>,
+}
+
+impl ReportEntry {
+    pub fn new(
+        input: InputInfo,
+        output: Option<&Result>,
+        status: Status,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            ratio: output
+                .filter(|_| input.size > 0)
+                .map(|output| output.total_size() as f64 / input.size as f64),
+            input_path: input.path,
+            input_size: input.size,
+            input_format: input.format,
+            input_quality: input.quality,
+            output_path: output.map(|output| output.path.clone()),
+            // every variant counts, `output_path` and `output_format` name the first
+            output_size: output.map(|output| output.total_size()),
+            output_format: output.and_then(|output| ImageFormat::from_extension(&output.extension)),
+            elapsed_ms: output.map(|output| output.elapsed),
+            status,
+            cached: output.map(|output| output.cached).unwrap_or(false),
+            error,
+        }
+    }
+
+    pub fn from_item(input: InputInfo, item: &BatchItem) -> Self {
+        match &item.result {
+            Ok(output) => Self::new(input, Some(output), Status::Success, None),
+            Err(err) => Self::new(input, None, err.status(), Some(err.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ReportSummary {
+    pub files: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub input_size: u64,
+    pub output_size: u64,
+    pub elapsed_ms: u64,
+}
+
+impl ReportSummary {
+    fn add(&mut self, entry: &ReportEntry) {
+        self.files += 1;
+        match entry.output_size {
+            Some(output_size) => {
+                self.succeeded += 1;
+                // only files with an output count towards the savings
+                self.input_size += entry.input_size;
+                self.output_size += output_size;
+            }
+            None => self.failed += 1,
+        }
+        self.elapsed_ms += entry.elapsed_ms.unwrap_or(0);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Report {
+    pub entries: Vec<ReportEntry>,
+    pub summary: ReportSummary,
+}
+
+const CSV_HEADER: &str = "input_path,input_size,input_format,input_quality,output_path,output_size,output_format,ratio,elapsed_ms,status,cached,error";
+
+/// Writes report entries as they are produced, in the chosen format.
+pub struct ReportWriter<W: Write> {
+    writer: W,
+    format: ReportFormat,
+    entries: Vec<ReportEntry>,
+    summary: ReportSummary,
+    started: bool,
+}
+
+impl<W: Write> ReportWriter<W> {
+    pub fn new(writer: W, format: ReportFormat) -> Self {
+        Self {
+            writer,
+            format,
+            entries: Vec::new(),
+            summary: ReportSummary::default(),
+            started: false,
+        }
+    }
+
+    pub fn write(&mut self, entry: ReportEntry) -> io::Result<()> {
+        self.summary.add(&entry);
+
+        match self.format {
+            ReportFormat::Ndjson => {
+                serde_json::to_writer(&mut self.writer, &entry)?;
+                writeln!(self.writer)?;
+                self.writer.flush()
+            }
+            ReportFormat::Json => {
+                self.entries.push(entry);
+                Ok(())
+            }
+            ReportFormat::Csv => {
+                if !self.started {
+                    writeln!(self.writer, "{}", CSV_HEADER)?;
+                    self.started = true;
+                }
+                writeln!(self.writer, "{}", csv_row(&entry))
+            }
+        }
+    }
+
+    pub fn summary(&self) -> &ReportSummary {
+        &self.summary
+    }
+
+    /// Write whatever is still buffered and hand back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.format {
+            ReportFormat::Json => {
+                let report = Report {
+                    entries: self.entries,
+                    summary: self.summary,
+                };
+                serde_json::to_writer_pretty(&mut self.writer, &report)?;
+                writeln!(self.writer)?;
+            }
+            // an empty batch still gets a header
+            ReportFormat::Csv if !self.started => writeln!(self.writer, "{}", CSV_HEADER)?,
+            _ => {}
+        }
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+fn csv_row(entry: &ReportEntry) -> String {
+    fn field<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+
+    let status = format!("{:?}", entry.status);
+    [
+        csv_escape(&entry.input_path),
+        entry.input_size.to_string(),
+        field(entry.input_format),
+        field(entry.input_quality),
+        csv_escape(&field(entry.output_path.as_ref())),
+        field(entry.output_size),
+        field(entry.output_format),
+        field(entry.ratio.map(|ratio| format!("{:.4}", ratio))),
+        field(entry.elapsed_ms),
+        status,
+        entry.cached.to_string(),
+        csv_escape(&field(entry.error.as_ref())),
+    ]
+    .join(",")
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compresser::{CompressError, Output};
+
+    fn entry(error: Option<&str>) -> ReportEntry {
+        let input = InputInfo {
+            path: "assets/a, b.jpg".to_string(),
+            size: 200,
+            format: Some(ImageFormat::Jpeg),
+            quality: Some(85.0),
+        };
+        let output = Result {
+            size: 50,
+            path: "assets/a, b.webp".to_string(),
+            elapsed: 12,
+            extension: "webp".to_string(),
+            warnings: Vec::new(),
+            cached: false,
+            outputs: Vec::new(),
+            selection: None,
+        };
+        match error {
+            Some(error) => ReportEntry::new(input, None, Status::Failed, Some(error.to_string())),
+            None => ReportEntry::new(input, Some(&output), Status::Success, None),
+        }
+    }
+
+    #[test]
+    fn test_csv_report() {
+        let mut writer = ReportWriter::new(Vec::new(), ReportFormat::Csv);
+        writer.write(entry(None)).unwrap();
+        writer.write(entry(Some("bad \"data\""))).unwrap();
+        let csv = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        let lines = csv.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "\"assets/a, b.jpg\",200,Jpeg,85,\"assets/a, b.webp\",50,WebP,0.2500,12,Success,false,"
+        );
+        assert_eq!(
+            lines[2],
+            "\"assets/a, b.jpg\",200,Jpeg,85,,,,,,Failed,false,\"bad \"\"data\"\"\""
+        );
+    }
+
+    #[test]
+    fn test_json_report_summary() {
+        let mut writer = ReportWriter::new(Vec::new(), ReportFormat::Json);
+        writer.write(entry(None)).unwrap();
+        writer.write(entry(Some("failed"))).unwrap();
+        let json = writer.finish().unwrap();
+
+        let report: Report = serde_json::from_slice(&json).unwrap();
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(report.summary.succeeded, 1);
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(report.summary.output_size, 50);
+    }
+
+    #[test]
+    fn test_unsupported_extension_status() {
+        let input = InputInfo {
+            path: "a.jpg".to_string(),
+            size: 200,
+            format: Some(ImageFormat::Jpeg),
+            quality: None,
+        };
+        let item = BatchItem {
+            path: "a.jpg".into(),
+            result: Err(CompressError::UnsupportedExtension("bmp".to_string())),
+        };
+
+        assert_eq!(
+            ReportEntry::from_item(input, &item).status,
+            Status::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_variant_sizes_summed() {
+        let input = InputInfo {
+            path: "hero.png".to_string(),
+            size: 1000,
+            format: Some(ImageFormat::Png),
+            quality: None,
+        };
+        let outputs = [("hero-400.webp", 100), ("hero-800.webp", 300)]
+            .iter()
+            .map(|(path, size)| Output {
+                path: path.to_string(),
+                size: *size,
+                extension: "webp".to_string(),
+                format: ImageFormat::WebP,
+                width: 0,
+                height: 0,
+            })
+            .collect::<Vec<Output>>();
+        let output = Result {
+            size: 100,
+            path: "hero-400.webp".to_string(),
+            elapsed: 12,
+            extension: "webp".to_string(),
+            warnings: Vec::new(),
+            cached: false,
+            outputs,
+            selection: None,
+        };
+
+        let entry = ReportEntry::new(input, Some(&output), Status::Success, None);
+        assert_eq!(entry.output_size, Some(400));
+        assert_eq!(entry.ratio, Some(0.4));
+    }
+}
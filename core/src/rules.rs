@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::classify::{self, ContentKind};
+use crate::compresser::CompressOptions;
+use crate::format_meta::ImageFormat;
+
+/// Routes files of one source format to a target format, e.g. `jpg` to `webp` at quality 80.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConversionRule {
+    /// source extension, e.g. `jpg` or `png`
+    pub from: String,
+    /// only match sources with (`true`) or without (`false`) transparency
+    #[serde(default)]
+    pub alpha: Option<bool>,
+    /// only match sources classified as this content, which decodes them
+    #[serde(default)]
+    pub content: Option<ContentKind>,
+    /// target extension
+    pub to: String,
+    /// falls back to the encoder settings when unset
+    pub quality: Option<f32>,
+    /// overrides the lossless setting of the target format when set
+    #[serde(default)]
+    pub lossless: Option<bool>,
+}
+
+impl ConversionRule {
+    pub fn new(from: &str, to: &str) -> Self {
+        Self {
+            from: from.to_string(),
+            alpha: None,
+            content: None,
+            to: to.to_string(),
+            quality: None,
+            lossless: None,
+        }
+    }
+
+    fn matches(
+        &self,
+        format: ImageFormat,
+        alpha: &mut dyn FnMut() -> bool,
+        content: &mut dyn FnMut() -> Option<ContentKind>,
+    ) -> bool {
+        ImageFormat::from_extension(&self.from) == Some(format)
+            && self.alpha.map_or(true, |expected| alpha() == expected)
+            && self
+                .content
+                .map_or(true, |expected| content() == Some(expected))
+    }
+
+    pub fn apply(&self, options: &CompressOptions) -> CompressOptions {
+        let mut options = options.clone();
+        options.extension = self.to.clone();
+        options.quality = self.quality;
+        match (ImageFormat::from_extension(&self.to), self.lossless) {
+            (Some(ImageFormat::WebP), Some(lossless)) => options.encoder.webp.lossless = lossless,
+            (Some(ImageFormat::Jpeg), Some(lossless)) => options.encoder.jpeg.lossless = lossless,
+            _ => {}
+        }
+
+        options
+    }
+}
+
+/// Options for `path` after applying the first matching rule of `options.rules`.
+///
+/// The returned options carry no rules, so routing an already routed file changes nothing.
+/// Files that match no rule, or whose format can't be detected, keep the batch options.
+pub fn route(options: &CompressOptions, path: &Path) -> CompressOptions {
+    if options.rules.is_empty() {
+        return options.clone();
+    }
+
+    let routed = ImageFormat::detect(path, options.detection)
+        .ok()
+        .and_then(|detected| {
+            // alpha and content are only read from the file when a rule asks for them, and at most once
+            let mut alpha = None;
+            let mut read_alpha =
+                || *alpha.get_or_insert_with(|| has_alpha(path, detected.format).unwrap_or(false));
+            let mut content = None;
+            let mut read_content = || {
+                *content.get_or_insert_with(|| {
+                    // decoded within the configured limits, checked from the header first
+                    classify::classify(&path.to_string_lossy(), options.detection, &options.limits)
+                        .ok()
+                        .map(|classification| classification.kind)
+                })
+            };
+
+            options
+                .rules
+                .iter()
+                .find(|rule| rule.matches(detected.format, &mut read_alpha, &mut read_content))
+                .map(|rule| rule.apply(options))
+        });
+
+    CompressOptions {
+        rules: Vec::new(),
+        ..routed.unwrap_or_else(|| options.clone())
+    }
+}
+
+/// Whether the image may contain transparent pixels, read from its header rather than by decoding.
+pub fn has_alpha(path: &Path, format: ImageFormat) -> io::Result<bool> {
+    let contents = fs::read(path)?;
+
+    Ok(match format {
+        ImageFormat::Jpeg => false,
+        ImageFormat::Png => png_has_alpha(&contents),
+        ImageFormat::WebP => {
+            webp::BitstreamFeatures::new(&contents).map_or(false, |features| features.has_alpha())
+        }
+        ImageFormat::Gif => gif_has_alpha(&contents),
+    })
+}
+
+fn png_has_alpha(contents: &[u8]) -> bool {
+    // color type in IHDR: 4 is grayscale with alpha, 6 is rgba
+    if matches!(contents.get(25), Some(4) | Some(6)) {
+        return true;
+    }
+
+    // palette and plain color types carry transparency in a tRNS chunk before the image data
+    let mut offset = 8;
+    while let Some(header) = contents.get(offset..offset + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..8] {
+            b"tRNS" => return true,
+            b"IDAT" => return false,
+            _ => offset += 12 + length,
+        }
+    }
+
+    false
+}
+
+fn gif_has_alpha(contents: &[u8]) -> bool {
+    // graphic control extensions with the transparent color flag set
+    contents
+        .windows(4)
+        .any(|block| block[..3] == [0x21, 0xF9, 0x04] && block[3] & 1 == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_route_by_alpha() {
+        let dir = std::env::temp_dir().join("ashuk-rules-route");
+        std::fs::create_dir_all(&dir).unwrap();
+        let opaque = dir.join("opaque.png");
+        let transparent = dir.join("transparent.png");
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 20, 20]))
+            .save(&opaque)
+            .unwrap();
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([200, 20, 20, 100]))
+            .save(&transparent)
+            .unwrap();
+
+        let options = CompressOptions {
+            extension: "jpg".to_string(),
+            rules: vec![
+                ConversionRule {
+                    alpha: Some(true),
+                    lossless: Some(true),
+                    ..ConversionRule::new("png", "webp")
+                },
+                ConversionRule::new("png", "png"),
+            ],
+            ..Default::default()
+        };
+
+        let routed = route(&options, &transparent);
+        assert_eq!(routed.extension, "webp");
+        assert!(routed.encoder.webp.lossless);
+        assert!(routed.rules.is_empty());
+
+        let routed = route(&options, &opaque);
+        assert_eq!(routed.extension, "png");
+        assert!(!routed.encoder.webp.lossless);
+
+        // rules without `lossless` keep the preset's setting
+        let mut options = CompressOptions {
+            rules: vec![ConversionRule::new("png", "webp")],
+            ..Default::default()
+        };
+        options.encoder.webp.lossless = true;
+        assert!(route(&options, &opaque).encoder.webp.lossless);
+    }
+}
@@ -0,0 +1,268 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use crate::auto::AUTO_EXTENSION;
+use crate::batch::BatchItem;
+use crate::compresser::{compress_to_target_extension, CompressOptions};
+use crate::format_meta::{ImageFormat, ProcessStrategy};
+use crate::rules;
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub path: PathBuf,
+    pub options: CompressOptions,
+}
+
+impl Job {
+    /// Job for `path`, with the conversion rules of `options` already resolved for that file.
+    pub fn new<P: Into<PathBuf>>(path: P, options: CompressOptions) -> Self {
+        let path = path.into();
+        let options = rules::route(&options, &path);
+
+        Self { path, options }
+    }
+
+    /// Formats `extension` may encode to, every candidate the source allows for an auto target
+    /// and the source's own format for an empty one.
+    fn target_formats(&self, extension: &str) -> Vec<ImageFormat> {
+        if !extension.is_empty() && extension != AUTO_EXTENSION {
+            return ImageFormat::from_extension(extension).into_iter().collect();
+        }
+
+        match ImageFormat::detect(&self.path, self.options.detection) {
+            Ok(detected) if extension.is_empty() => vec![detected.format],
+            // a misspelled candidate is reported by the compression
+            Ok(detected) => self
+                .options
+                .auto
+                .candidates(detected.format)
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Strategy of the encoder that will run, which depends on the target format rather than the input.
+    pub fn strategy(&self) -> ProcessStrategy {
+        let extensions = match &self.options.variants {
+            Some(variants) if !variants.formats.is_empty() => variants.formats.iter().collect(),
+            _ => vec![&self.options.extension],
+        };
+
+        // one serial encoder is enough to make the whole job serial
+        let serial = extensions
+            .into_iter()
+            .flat_map(|extension| self.target_formats(extension))
+            .any(|format| matches!(format.process_strategy(), ProcessStrategy::Serial));
+        if serial {
+            ProcessStrategy::Serial
+        } else {
+            ProcessStrategy::Parallel
+        }
+    }
+}
+
+pub enum ScheduleEvent<'a> {
+    Started(&'a Path),
+    Finished(BatchItem),
+}
+
+/// Runs compression jobs within a global thread budget.
+///
+/// Parallel-safe jobs are spread over worker threads holding one thread each,
+/// while serial jobs (oxipng, which is multithreaded itself) run one at a time on a rayon pool
+/// sized to the share of the budget that is not needed by the workers.
+#[derive(Debug, Clone, Copy)]
+pub struct Scheduler {
+    threads: usize,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(rayon::current_num_threads())
+    }
+}
+
+impl Scheduler {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Run every job, reporting when each one starts and finishes. Returns once all jobs are done.
+    pub fn run<F>(&self, jobs: Vec<Job>, on_event: F)
+    where
+        F: Fn(ScheduleEvent) + Sync,
+    {
+        let (serial, parallel): (Vec<Job>, Vec<Job>) = jobs
+            .into_iter()
+            .partition(|job| matches!(job.strategy(), ProcessStrategy::Serial));
+
+        let budget = Budget::new(self.threads);
+        let queue = Mutex::new(VecDeque::from(parallel));
+        let workers = self.threads.min(queue.lock().unwrap().len());
+        // oxipng runs alongside the workers with half of the budget, or alone with all of it
+        let shared = (self.threads / 2).max(1);
+        let (shared_pool, full_pool) = if serial.is_empty() {
+            (None, None)
+        } else {
+            (build_pool(shared), build_pool(self.threads))
+        };
+
+        // serial jobs run on the calling thread, a pool thread would pick up parallel jobs
+        // while waiting on oxipng and could block on the budget the serial job holds
+        rayon::in_place_scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|_| loop {
+                    let job = match queue.lock().unwrap().pop_front() {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    budget.acquire(1);
+                    run_job(&job, &on_event);
+                    budget.release(1);
+                });
+            }
+
+            for job in &serial {
+                // take the whole budget once no parallel-safe job is waiting
+                let (share, pool) = if queue.lock().unwrap().is_empty() {
+                    (self.threads, &full_pool)
+                } else {
+                    (shared, &shared_pool)
+                };
+
+                budget.acquire(share);
+                match pool {
+                    Some(pool) => pool.install(|| run_job(job, &on_event)),
+                    None => run_job(job, &on_event),
+                }
+                budget.release(share);
+            }
+        });
+    }
+}
+
+fn run_job<F>(job: &Job, on_event: &F)
+where
+    F: Fn(ScheduleEvent) + Sync,
+{
+    on_event(ScheduleEvent::Started(&job.path));
+    let result = compress_to_target_extension(&job.path.to_string_lossy(), job.options.clone());
+    on_event(ScheduleEvent::Finished(BatchItem {
+        path: job.path.clone(),
+        result,
+    }));
+}
+
+fn build_pool(threads: usize) -> Option<ThreadPool> {
+    // oxipng blocks one thread while waiting on work it spawned into the same pool,
+    // so a single threaded pool would never make progress
+    ThreadPoolBuilder::new()
+        .num_threads(threads.max(2))
+        .build()
+        .ok()
+}
+
+/// Counting semaphore handing out threads in request order, so a large request is not starved by small ones.
+struct Budget {
+    state: Mutex<BudgetState>,
+    changed: Condvar,
+}
+
+struct BudgetState {
+    available: usize,
+    next_ticket: u64,
+    serving: u64,
+}
+
+impl Budget {
+    fn new(threads: usize) -> Self {
+        Self {
+            state: Mutex::new(BudgetState {
+                available: threads,
+                next_ticket: 0,
+                serving: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, threads: usize) {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket || state.available < threads {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.available -= threads;
+        state.serving += 1;
+        self.changed.notify_all();
+    }
+
+    fn release(&self, threads: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.available += threads;
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_run_mixed_jobs() {
+        let root = std::env::temp_dir().join("ashuk-scheduler-run");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let image =
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 128]));
+
+        let jobs = ["jpg", "webp", "png"]
+            .iter()
+            .enumerate()
+            .map(|(i, extension)| {
+                let path = root.join(format!("{}.png", i));
+                image.save(&path).unwrap();
+                Job::new(
+                    path,
+                    CompressOptions {
+                        extension: extension.to_string(),
+                        quality: None,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect::<Vec<Job>>();
+
+        let started = AtomicUsize::new(0);
+        let finished = Mutex::new(Vec::new());
+        Scheduler::new(2).run(jobs, |event| match event {
+            ScheduleEvent::Started(_) => {
+                started.fetch_add(1, Ordering::SeqCst);
+            }
+            ScheduleEvent::Finished(item) => finished.lock().unwrap().push(item),
+        });
+
+        let finished = finished.into_inner().unwrap();
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+        assert_eq!(finished.len(), 3);
+        // png to jpg is not supported and must be reported, not dropped
+        assert_eq!(
+            finished.iter().filter(|item| item.result.is_ok()).count(),
+            2
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::PathBuf;
+use std::result;
+
+use crate::auto::AUTO_EXTENSION;
+use crate::compresser::{self, CompressError, CompressOptions};
+use crate::curve::{CurveOptions, CurvePoint, QualityCurve};
+use crate::format_meta::ImageFormat;
+use crate::preview::Source;
+use crate::scheduler::Job;
+
+// encodes checking a plan against the budget, each one lowering the target when it overshoots
+const MAX_PASSES: usize = 4;
+// extra room taken off the target after an overshoot, estimates are rarely exact
+const OVERSHOOT_MARGIN: f64 = 0.97;
+
+/// A total size for a whole batch, shared out by giving every file about the same perceptual loss.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SizeBudget {
+    pub total_bytes: u64,
+    #[serde(default)]
+    pub curve: CurveOptions,
+}
+
+/// Quality given to one file of the batch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Allocation {
+    pub path: PathBuf,
+    /// `None` when the file couldn't be estimated and keeps its own quality
+    pub quality: Option<f32>,
+    /// size of the full encode at `quality`, or of the input for a file that couldn't be estimated
+    pub bytes: Option<u64>,
+    /// estimated SSIM at `quality`
+    pub ssim: Option<f64>,
+    /// `quality` is the smallest point of the file's curve, the budget can't lower it further
+    #[serde(default)]
+    pub at_minimum: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BudgetPlan {
+    pub total_bytes: u64,
+    /// combined size of every file, the ones that couldn't be estimated counted at their input size
+    pub planned_bytes: u64,
+    /// false as well when the size of a file is unknown
+    pub fits: bool,
+    pub allocations: Vec<Allocation>,
+}
+
+impl BudgetPlan {
+    /// Set the quality of every planned job. Jobs are matched by path.
+    pub fn apply(&self, jobs: &mut [Job]) {
+        for job in jobs.iter_mut() {
+            let quality = self
+                .allocations
+                .iter()
+                .find(|allocation| allocation.path == job.path)
+                .and_then(|allocation| allocation.quality);
+            if quality.is_some() {
+                job.options.quality = quality;
+            }
+        }
+    }
+
+    /// Lowest estimated SSIM across the batch.
+    pub fn min_ssim(&self) -> Option<f64> {
+        self.allocations
+            .iter()
+            .filter_map(|allocation| allocation.ssim)
+            .min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+    }
+}
+
+// the options a job is encoded with, its format filled in when it keeps its own
+fn job_options(job: &Job, source: ImageFormat) -> result::Result<CompressOptions, CompressError> {
+    let extension = job.options.target_extension(source);
+    // one quality for several files of several formats isn't something a curve can describe,
+    // nor is a format only chosen after encoding
+    let option = if job.options.variants.is_some() {
+        Some("variants")
+    } else if extension == AUTO_EXTENSION {
+        Some(AUTO_EXTENSION)
+    } else {
+        None
+    };
+    if let Some(option) = option {
+        return Err(CompressError::Unestimable {
+            path: job.path.to_string_lossy().to_string(),
+            option,
+        });
+    }
+
+    Ok(CompressOptions {
+        extension,
+        ..job.options.clone()
+    })
+}
+
+fn estimate(job: &Job, curve: &CurveOptions) -> result::Result<QualityCurve, CompressError> {
+    let source = Source::open(
+        &job.path.to_string_lossy(),
+        job.options.detection,
+        &job.options.limits,
+    )?;
+    source.quality_curve(&job_options(job, source.format)?, curve)
+}
+
+// actual size of the output at `quality`, encoded the way the job will write it
+fn measure(job: &Job, quality: f32) -> result::Result<u64, CompressError> {
+    let path = job.path.to_string_lossy();
+    let detected = compresser::detect(&path, job.options.detection)?;
+    let mut options = CompressOptions {
+        quality: Some(quality),
+        ..job_options(job, detected.format)?
+    };
+    let format = ImageFormat::from_extension(&options.extension)
+        .ok_or_else(|| CompressError::UnsupportedExtension(options.extension.clone()))?;
+    if !detected.format.can_compress(&format) {
+        return Err(CompressError::Unsupported(detected.format, format));
+    }
+
+    let encoded = compresser::encode_file(
+        &path,
+        detected.format,
+        format,
+        &mut options,
+        None,
+        &mut Vec::new(),
+    )?;
+    Ok(encoded.bytes.len() as u64)
+}
+
+// cheapest point at least as faithful as `ssim`, or the most faithful one when none is
+fn cheapest(curve: &QualityCurve, ssim: f64) -> Option<&CurvePoint> {
+    curve
+        .points
+        .iter()
+        .filter(|point| point.ssim >= ssim)
+        .min_by_key(|point| point.bytes)
+        .or_else(|| {
+            curve
+                .points
+                .iter()
+                .max_by(|a, b| a.ssim.partial_cmp(&b.ssim).unwrap_or(Ordering::Equal))
+        })
+}
+
+/// Pick a point of every curve so the total stays within `total_bytes`, at the highest SSIM every file can reach.
+///
+/// When even the smallest points don't fit, the smallest points are returned.
+pub fn allocate(curves: &[&QualityCurve], total_bytes: u64) -> Vec<CurvePoint> {
+    let mut targets = curves
+        .iter()
+        .flat_map(|curve| curve.points.iter().map(|point| point.ssim))
+        .collect::<Vec<f64>>();
+    targets.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    targets.dedup();
+
+    for target in targets {
+        let points = curves
+            .iter()
+            .filter_map(|curve| cheapest(curve, target).copied())
+            .collect::<Vec<CurvePoint>>();
+        if points.iter().map(|point| point.bytes).sum::<u64>() <= total_bytes {
+            return points;
+        }
+    }
+
+    curves
+        .iter()
+        .filter_map(|curve| curve.points.iter().min_by_key(|point| point.bytes).copied())
+        .collect()
+}
+
+/// Estimate every job and choose its quality so the whole batch fits `budget`.
+///
+/// Plans are checked by encoding every file in memory at its chosen quality,
+/// and tightened while the real total overshoots. Nothing is written.
+pub fn plan(jobs: &[Job], budget: &SizeBudget) -> BudgetPlan {
+    let curves = jobs
+        .par_iter()
+        .map(|job| estimate(job, &budget.curve))
+        .collect::<Vec<result::Result<QualityCurve, CompressError>>>();
+    let estimated = jobs
+        .iter()
+        .zip(&curves)
+        .filter_map(|(job, curve)| curve.as_ref().ok().map(|curve| (job, curve)))
+        .collect::<Vec<(&Job, &QualityCurve)>>();
+    let estimated_curves = estimated
+        .iter()
+        .map(|(_, curve)| *curve)
+        .collect::<Vec<&QualityCurve>>();
+
+    // files that can't be estimated are still written, about their current size is kept for them
+    let reserved = jobs
+        .iter()
+        .zip(&curves)
+        .filter(|(_, curve)| curve.is_err())
+        .map(|(job, _)| {
+            (
+                job.path.clone(),
+                fs::metadata(&job.path).ok().map(|metadata| metadata.len()),
+            )
+        })
+        .collect::<Vec<(PathBuf, Option<u64>)>>();
+    let reserved_bytes = reserved.iter().filter_map(|(_, size)| *size).sum::<u64>();
+    let available = budget.total_bytes.saturating_sub(reserved_bytes);
+
+    let mut target = available;
+    let mut points = Vec::new();
+    let mut sizes = Vec::new();
+    for _ in 0..MAX_PASSES {
+        let chosen = allocate(&estimated_curves, target);
+        let measured = estimated
+            .par_iter()
+            .zip(&chosen)
+            .map(|((job, _), point)| measure(job, point.quality))
+            .collect::<Vec<result::Result<u64, CompressError>>>();
+        let total = measured
+            .iter()
+            .filter_map(|size| size.as_ref().ok())
+            .sum::<u64>();
+
+        let unchanged = chosen == points;
+        points = chosen;
+        sizes = measured;
+        // the same points as last time won't measure any smaller
+        if total <= available || total == 0 || unchanged {
+            break;
+        }
+        target = (target as f64 * available as f64 / total as f64 * OVERSHOOT_MARGIN) as u64;
+    }
+
+    let mut planned = estimated
+        .iter()
+        .zip(points.iter().zip(sizes))
+        .map(|((job, curve), (point, size))| {
+            let at_minimum = curve.points.iter().all(|other| other.bytes >= point.bytes);
+            (job.path.clone(), (point, size, at_minimum))
+        })
+        .collect::<Vec<_>>();
+    let allocations = jobs
+        .iter()
+        .zip(curves)
+        .map(|(job, curve)| {
+            let error = match curve {
+                Ok(_) => None,
+                Err(err) => Some(err.to_string()),
+            };
+            let position = planned.iter().position(|(path, _)| *path == job.path);
+            match position.map(|i| planned.remove(i).1) {
+                Some((point, size, at_minimum)) => Allocation {
+                    path: job.path.clone(),
+                    quality: Some(point.quality),
+                    ssim: Some(point.ssim),
+                    at_minimum,
+                    error: size.as_ref().err().map(|err| err.to_string()),
+                    bytes: size.ok(),
+                },
+                None => Allocation {
+                    path: job.path.clone(),
+                    quality: None,
+                    bytes: reserved
+                        .iter()
+                        .find(|(path, _)| *path == job.path)
+                        .and_then(|(_, size)| *size),
+                    ssim: None,
+                    at_minimum: false,
+                    error,
+                },
+            }
+        })
+        .collect::<Vec<Allocation>>();
+
+    let planned_bytes = allocations
+        .iter()
+        .filter_map(|allocation| allocation.bytes)
+        .sum::<u64>();
+    let unknown = allocations
+        .iter()
+        .any(|allocation| allocation.bytes.is_none());
+    BudgetPlan {
+        total_bytes: budget.total_bytes,
+        planned_bytes,
+        fits: planned_bytes <= budget.total_bytes && !unknown,
+        allocations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compresser::{OutputLocation, Variants};
+
+    fn curve(points: &[(f32, u64, f64)]) -> QualityCurve {
+        QualityCurve {
+            format: ImageFormat::Jpeg,
+            points: points
+                .iter()
+                .map(|&(quality, bytes, ssim)| CurvePoint {
+                    quality,
+                    bytes,
+                    ssim,
+                })
+                .collect(),
+            estimated: false,
+        }
+    }
+
+    #[test]
+    fn test_allocate_equalizes_ssim() {
+        // a detailed image costs far more than a flat one for the same fidelity
+        let detailed = curve(&[(50.0, 400, 0.85), (75.0, 700, 0.92), (95.0, 1500, 0.98)]);
+        let flat = curve(&[(50.0, 50, 0.93), (75.0, 80, 0.97), (95.0, 150, 0.99)]);
+
+        let points = allocate(&[&detailed, &flat], 900);
+        assert_eq!(points[0].quality, 75.0);
+        assert_eq!(points[1].quality, 50.0);
+
+        let points = allocate(&[&detailed, &flat], 100);
+        assert_eq!(points[0].quality, 50.0);
+        assert_eq!(points[1].quality, 50.0);
+    }
+
+    #[test]
+    fn test_measure_follows_the_job() {
+        let dir = std::env::temp_dir().join("ashuk-budget-measure");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.jpg");
+        image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+        })
+        .save(&input)
+        .unwrap();
+
+        // a lossless transcode ignores the quality, so the measure must too
+        let mut options = CompressOptions {
+            extension: "jpg".to_string(),
+            output: OutputLocation::Dir(dir.join("out")),
+            ..Default::default()
+        };
+        options.encoder.jpeg.lossless = true;
+        let job = Job::new(&input, options);
+        let written =
+            compresser::compress_to_target_extension(&input.to_string_lossy(), job.options.clone())
+                .unwrap();
+        assert_eq!(measure(&job, 20.0).unwrap(), written.size);
+
+        let variants = Job::new(
+            &input,
+            CompressOptions {
+                variants: Some(Variants::default()),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            measure(&variants, 20.0),
+            Err(CompressError::Unestimable { .. })
+        ));
+
+        let auto = Job::new(
+            &input,
+            CompressOptions {
+                extension: AUTO_EXTENSION.to_string(),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            measure(&auto, 20.0),
+            Err(CompressError::Unestimable {
+                option: AUTO_EXTENSION,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_plan_reserves_unestimated() {
+        let dir = std::env::temp_dir().join("ashuk-budget-reserve");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let photo = dir.join("photo.jpg");
+        let hero = dir.join("hero.jpg");
+        for path in [&photo, &hero] {
+            image::RgbImage::from_fn(64, 48, |x, y| {
+                image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+            })
+            .save(path)
+            .unwrap();
+        }
+        let hero_size = fs::metadata(&hero).unwrap().len();
+
+        let options = CompressOptions {
+            extension: "jpg".to_string(),
+            ..Default::default()
+        };
+        let jobs = vec![
+            Job::new(&photo, options.clone()),
+            Job::new(
+                &hero,
+                CompressOptions {
+                    variants: Some(Variants::default()),
+                    ..options
+                },
+            ),
+        ];
+        let budget = |total_bytes| SizeBudget {
+            total_bytes,
+            curve: CurveOptions::default(),
+        };
+
+        let roomy = plan(&jobs, &budget(hero_size * 10));
+        assert_eq!(roomy.allocations[1].bytes, Some(hero_size));
+        assert!(roomy.planned_bytes > hero_size);
+        assert!(roomy.fits);
+
+        // the variants alone take the whole budget
+        assert!(!plan(&jobs, &budget(hero_size)).fits);
+    }
+}
@@ -0,0 +1,365 @@
+use image::imageops;
+use image::{DynamicImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::time::Instant;
+
+use crate::compresser::{
+    self, compress_to_target_extension, CompressError, CompressOptions, Output, OutputLocation,
+    Stage,
+};
+use crate::encoder::{self, JPEG_MAX_DIMENSION};
+use crate::format_meta::{FormatDetection, ImageFormat};
+use crate::limits::{Header, Limits};
+use crate::transform::Resize;
+use crate::watermark;
+
+const GLYPH_SIZE: u32 = 8;
+const LABEL_MARGIN: u32 = 4;
+const LABEL_COLOR: [u8; 3] = [40, 40, 40];
+// stands in for a file path in errors about the sheet itself
+const SHEET_PATH: &str = "contact sheet";
+
+/// Small previews written next to the inputs, e.g. `photo-thumb.webp`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ThumbnailOptions {
+    /// longest side in pixels
+    pub size: u32,
+    pub extension: String,
+    pub quality: Option<f32>,
+    pub suffix: String,
+    pub output: OutputLocation,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            extension: "webp".to_string(),
+            quality: None,
+            suffix: "-thumb".to_string(),
+            output: OutputLocation::SameDir,
+        }
+    }
+}
+
+impl ThumbnailOptions {
+    /// Options for the regular pipeline, so thumbnails of a folder can be written by a `Batch`.
+    pub fn compress_options(&self) -> CompressOptions {
+        CompressOptions {
+            quality: self.quality,
+            extension: self.extension.clone(),
+            output: self.output.clone(),
+            suffix: Some(self.suffix.clone()),
+            resize: Some(Resize {
+                max_width: Some(self.size),
+                max_height: Some(self.size),
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn write_thumbnail(
+    file_path: &str,
+    options: &ThumbnailOptions,
+) -> result::Result<compresser::Result, CompressError> {
+    compress_to_target_extension(file_path, options.compress_options())
+}
+
+/// Layout of a contact sheet tiling many images, with the file name under each tile.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ContactSheet {
+    pub columns: u32,
+    /// longest side of each tile in pixels
+    pub tile_size: u32,
+    pub padding: u32,
+    pub labels: bool,
+    pub background: [u8; 3],
+    pub detection: FormatDetection,
+    pub limits: Limits,
+}
+
+impl Default for ContactSheet {
+    fn default() -> Self {
+        Self {
+            columns: 6,
+            tile_size: 200,
+            padding: 8,
+            labels: true,
+            background: [255, 255, 255],
+            detection: FormatDetection::Content,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// A rendered sheet, and the files that couldn't be placed on it.
+pub struct Sheet {
+    pub image: DynamicImage,
+    pub skipped: Vec<(PathBuf, CompressError)>,
+}
+
+impl ContactSheet {
+    fn label_height(&self) -> u32 {
+        if self.labels {
+            GLYPH_SIZE + LABEL_MARGIN
+        } else {
+            0
+        }
+    }
+
+    // canvas for `tiles` tiles, it grows a row every `columns` files
+    fn canvas_size(&self, tiles: usize) -> (u32, u32) {
+        let tile_size = self.tile_size.max(1);
+        let tiles = u32::try_from(tiles).unwrap_or(u32::MAX);
+        let columns = self.columns.max(1).min(tiles.max(1));
+        let rows = (tiles / columns + u32::from(tiles % columns != 0)).max(1);
+        let cell_width = tile_size.saturating_add(self.padding);
+        let cell_height = tile_size
+            .saturating_add(self.label_height())
+            .saturating_add(self.padding);
+
+        (
+            columns
+                .saturating_mul(cell_width)
+                .saturating_add(self.padding),
+            rows.saturating_mul(cell_height)
+                .saturating_add(self.padding),
+        )
+    }
+
+    /// Tile `paths` in order. Files that fail to decode are left out and reported in `Sheet::skipped`.
+    ///
+    /// The canvas for every path is checked against `limits` before anything is decoded,
+    /// so a folder too large for one sheet is an error rather than a huge allocation.
+    pub fn render<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+    ) -> result::Result<Sheet, CompressError> {
+        let (width, height) = self.canvas_size(paths.len());
+        self.limits.check(
+            SHEET_PATH,
+            &Header {
+                width,
+                height,
+                bytes_per_pixel: 4,
+                frames: 1,
+            },
+        )?;
+
+        let tile_size = self.tile_size.max(1);
+        let decoded = paths
+            .par_iter()
+            .map(|path| {
+                let path = path.as_ref();
+                thumbnail(path, tile_size, self.detection, &self.limits)
+                    .map(|image| (path.to_path_buf(), image))
+                    .map_err(|err| (path.to_path_buf(), err))
+            })
+            .collect::<Vec<result::Result<(PathBuf, DynamicImage), (PathBuf, CompressError)>>>();
+
+        let mut tiles = Vec::new();
+        let mut skipped = Vec::new();
+        for tile in decoded {
+            match tile {
+                Ok(tile) => tiles.push(tile),
+                Err(err) => skipped.push(err),
+            }
+        }
+
+        let columns = self.columns.max(1).min(tiles.len().max(1) as u32);
+        let cell_width = tile_size + self.padding;
+        let cell_height = tile_size + self.label_height() + self.padding;
+
+        let [r, g, b] = self.background;
+        let (width, height) = self.canvas_size(tiles.len());
+        let mut canvas = RgbaImage::from_pixel(width, height, Rgba([r, g, b, 255]));
+
+        for (i, (path, image)) in tiles.iter().enumerate() {
+            let x = self.padding + (i as u32 % columns) * cell_width;
+            let y = self.padding + (i as u32 / columns) * cell_height;
+
+            // center the tile in its cell
+            let offset_x = (tile_size - image.width()) / 2;
+            let offset_y = (tile_size - image.height()) / 2;
+            imageops::overlay(
+                &mut canvas,
+                &image.to_rgba8(),
+                (x + offset_x) as i64,
+                (y + offset_y) as i64,
+            );
+
+            if self.labels {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let label = fit_label(&name, (tile_size / GLYPH_SIZE) as usize);
+                let label_width = label.len() as u32 * GLYPH_SIZE;
+                imageops::overlay(
+                    &mut canvas,
+                    &watermark::render_text(&label, LABEL_COLOR),
+                    (x + (tile_size - label_width) / 2) as i64,
+                    (y + tile_size + LABEL_MARGIN / 2) as i64,
+                );
+            }
+        }
+
+        Ok(Sheet {
+            image: DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+            skipped,
+        })
+    }
+
+    /// Render `paths` and encode the sheet to `output_path`, in the format of its extension.
+    pub fn write<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+        output_path: &str,
+        options: &CompressOptions,
+    ) -> result::Result<compresser::Result, CompressError> {
+        let start = Instant::now();
+
+        let extension = Path::new(output_path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let format = ImageFormat::from_extension(&extension)
+            .filter(|format| ImageFormat::get_formats().contains(format))
+            .ok_or_else(|| CompressError::UnsupportedExtension(extension.clone()))?;
+
+        // checked before decoding any tile, the encoder would only reject the sheet at the end
+        let (width, height) = self.canvas_size(paths.len());
+        if format == ImageFormat::Jpeg && width.max(height) > JPEG_MAX_DIMENSION {
+            return Err(CompressError::Dimensions {
+                path: output_path.to_string(),
+                codec: format,
+                width,
+                height,
+                max: JPEG_MAX_DIMENSION,
+            });
+        }
+
+        let sheet = self.render(paths)?;
+        let contents = encoder::encode(&sheet.image, format, options, output_path)?;
+        fs::write(output_path, &contents).map_err(CompressError::io(output_path, Stage::Write))?;
+
+        let output = Output {
+            path: output_path.to_string(),
+            size: contents.len() as u64,
+            extension,
+            format,
+            width: sheet.image.width(),
+            height: sheet.image.height(),
+        };
+        Ok(compresser::Result {
+            size: output.size,
+            path: output.path.clone(),
+            elapsed: start.elapsed().as_millis() as u64,
+            extension: output.extension.clone(),
+            warnings: sheet
+                .skipped
+                .iter()
+                .map(|(path, err)| format!("{} was left out: {}", path.to_string_lossy(), err))
+                .collect(),
+            cached: false,
+            outputs: vec![output],
+            selection: None,
+        })
+    }
+}
+
+/// Decode `path` within `limits` and shrink it to fit within `size`; smaller images are kept as they are.
+pub fn thumbnail(
+    path: &Path,
+    size: u32,
+    detection: FormatDetection,
+    limits: &Limits,
+) -> result::Result<DynamicImage, CompressError> {
+    let file_path = path.to_string_lossy();
+    let detected = compresser::detect(&file_path, detection)?;
+    let image = compresser::decode(&file_path, detected.format, limits)?;
+
+    if image.width() <= size && image.height() <= size {
+        Ok(image)
+    } else {
+        Ok(image.thumbnail(size, size))
+    }
+}
+
+/// Shorten `name` to `max_chars`, keeping the end where the extension is.
+fn fit_label(name: &str, max_chars: usize) -> String {
+    // the bitmap font only covers ASCII
+    let chars = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .collect::<Vec<char>>();
+    if chars.len() <= max_chars {
+        return chars.into_iter().collect();
+    }
+    if max_chars <= 2 {
+        return chars[..max_chars].iter().collect();
+    }
+
+    let keep = max_chars - 2;
+    let head = keep / 2;
+    let tail = keep - head;
+    chars[..head]
+        .iter()
+        .chain(['.', '.'].iter())
+        .chain(chars[chars.len() - tail..].iter())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_fit_label() {
+        assert_eq!(fit_label("a.jpg", 10), "a.jpg");
+        assert_eq!(fit_label("holiday-photo.jpg", 10), "holi...jpg");
+        assert_eq!(fit_label("café.png", 10), "caf?.png");
+    }
+
+    #[test]
+    fn test_render_contact_sheet() {
+        let dir = std::env::temp_dir().join("ashuk-thumbnail-sheet");
+        fs::create_dir_all(&dir).unwrap();
+        let wide = dir.join("wide.png");
+        let broken = dir.join("broken.png");
+        image::RgbImage::from_pixel(300, 100, image::Rgb([0, 120, 200]))
+            .save(&wide)
+            .unwrap();
+        fs::write(&broken, b"\x89PNG\r\n\x1a\nbroken").unwrap();
+
+        let sheet = ContactSheet {
+            columns: 4,
+            tile_size: 100,
+            padding: 10,
+            ..Default::default()
+        };
+        let rendered = sheet
+            .render(&[wide.clone(), broken.clone(), wide.clone()])
+            .unwrap();
+
+        // two tiles in one row: 10 + 2 * (100 + 10) by 10 + 100 + label + 10
+        assert_eq!(rendered.image.width(), 230);
+        assert_eq!(rendered.image.height(), 132);
+        assert_eq!(rendered.skipped.len(), 1);
+        assert_eq!(rendered.skipped[0].0, broken);
+
+        // 10k files at the default limits, rejected before decoding any of them
+        let many = vec![wide; 10_000];
+        assert!(matches!(
+            ContactSheet::default().render(&many),
+            Err(CompressError::LimitExceeded { .. })
+        ));
+    }
+}
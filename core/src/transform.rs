@@ -0,0 +1,321 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, Frame, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+use crate::compresser::CompressOptions;
+
+/// Shrink images to fit within the given bounds, keeping the aspect ratio. Images are never enlarged.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct Resize {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+}
+
+impl Resize {
+    pub fn target_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let max_width = self.max_width.unwrap_or(width).min(width);
+        let max_height = self.max_height.unwrap_or(height).min(height);
+        if (max_width, max_height) == (width, height) {
+            return (width, height);
+        }
+
+        let scale = f64::min(
+            max_width as f64 / width as f64,
+            max_height as f64 / height as f64,
+        );
+        let target_width = ((width as f64 * scale).round() as u32).max(1);
+        let target_height = ((height as f64 * scale).round() as u32).max(1);
+
+        (target_width, target_height)
+    }
+
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let (width, height) = self.target_size(image.width(), image.height());
+        if (width, height) == (image.width(), image.height()) {
+            return image;
+        }
+
+        image.resize_exact(width, height, FilterType::Lanczos3)
+    }
+}
+
+/// Width to height ratio, e.g. 16:9.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct AspectRatio {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl AspectRatio {
+    /// Largest region of this ratio that fits in `width` × `height`.
+    pub fn fit(&self, width: u32, height: u32) -> (u32, u32) {
+        let (ratio_width, ratio_height) = (self.width.max(1) as u64, self.height.max(1) as u64);
+        if width as u64 * ratio_height > height as u64 * ratio_width {
+            let crop_width = (height as u64 * ratio_width / ratio_height) as u32;
+            (crop_width.max(1), height)
+        } else {
+            let crop_height = (width as u64 * ratio_height / ratio_width) as u32;
+            (width, crop_height.max(1))
+        }
+    }
+}
+
+/// Which part of the image a crop keeps.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Crop {
+    /// Keep this rectangle, clamped to the image.
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Keep the largest region of `aspect`, placed by `gravity`.
+    Gravity {
+        aspect: AspectRatio,
+        #[serde(default)]
+        gravity: Gravity,
+    },
+    /// Keep the largest region of `aspect` with the most detail, measured by edges and colour saturation.
+    Smart { aspect: AspectRatio },
+}
+
+// longest side of the copy analysed by smart crops
+const SMART_CROP_ANALYSIS_SIZE: u32 = 256;
+
+impl Crop {
+    /// Region `(x, y, width, height)` of `image` this crop keeps.
+    pub fn region(&self, image: &DynamicImage) -> (u32, u32, u32, u32) {
+        let (width, height) = image.dimensions();
+
+        match *self {
+            Crop::Rect {
+                x,
+                y,
+                width: crop_width,
+                height: crop_height,
+            } => {
+                let x = x.min(width.saturating_sub(1));
+                let y = y.min(height.saturating_sub(1));
+                (
+                    x,
+                    y,
+                    crop_width.min(width - x).max(1),
+                    crop_height.min(height - y).max(1),
+                )
+            }
+            Crop::Gravity { aspect, gravity } => {
+                let (crop_width, crop_height) = aspect.fit(width, height);
+                let (free_x, free_y) = (width - crop_width, height - crop_height);
+                let x = match gravity {
+                    Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+                    Gravity::East | Gravity::NorthEast | Gravity::SouthEast => free_x,
+                    _ => free_x / 2,
+                };
+                let y = match gravity {
+                    Gravity::North | Gravity::NorthWest | Gravity::NorthEast => 0,
+                    Gravity::South | Gravity::SouthWest | Gravity::SouthEast => free_y,
+                    _ => free_y / 2,
+                };
+                (x, y, crop_width, crop_height)
+            }
+            Crop::Smart { aspect } => {
+                let (crop_width, crop_height) = aspect.fit(width, height);
+                let (x, y) = smart_offset(image, crop_width, crop_height);
+                (x, y, crop_width, crop_height)
+            }
+        }
+    }
+
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let (x, y, width, height) = self.region(&image);
+        if (x, y, width, height) == (0, 0, image.width(), image.height()) {
+            return image;
+        }
+
+        image.crop_imm(x, y, width, height)
+    }
+}
+
+/// Slide a `crop_width` × `crop_height` window along the free axis and return the offset with the most detail.
+fn smart_offset(image: &DynamicImage, crop_width: u32, crop_height: u32) -> (u32, u32) {
+    let (width, height) = image.dimensions();
+    if (crop_width, crop_height) == (width, height) {
+        return (0, 0);
+    }
+
+    // analyse a small copy, detail is about structure rather than single pixels
+    let small = if width.max(height) > SMART_CROP_ANALYSIS_SIZE {
+        image.thumbnail(SMART_CROP_ANALYSIS_SIZE, SMART_CROP_ANALYSIS_SIZE)
+    } else {
+        image.clone()
+    };
+    let rgb = small.to_rgb8();
+    let (small_width, small_height) = rgb.dimensions();
+    let scale = small_width as f64 / width as f64;
+
+    // detail of every pixel: gradient of luma plus a bit of saturation, so flat colourful areas count too
+    let luma = |x: u32, y: u32| {
+        let [r, g, b] = rgb.get_pixel(x, y).0;
+        0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+    };
+    let detail = |x: u32, y: u32| {
+        let right = luma((x + 1).min(small_width - 1), y);
+        let below = luma(x, (y + 1).min(small_height - 1));
+        let here = luma(x, y);
+        let [r, g, b] = rgb.get_pixel(x, y).0;
+        let saturation = r.max(g).max(b) as f64 - r.min(g).min(b) as f64;
+        (right - here).abs() + (below - here).abs() + saturation * 0.25
+    };
+
+    let horizontal = crop_width < width;
+    let (length, window) = if horizontal {
+        let window = ((crop_width as f64 * scale).round() as u32).clamp(1, small_width);
+        (small_width, window)
+    } else {
+        let window = ((crop_height as f64 * scale).round() as u32).clamp(1, small_height);
+        (small_height, window)
+    };
+
+    // detail summed per column (or row), then a sliding window over those sums
+    let profile = (0..length)
+        .map(|i| {
+            if horizontal {
+                (0..small_height).map(|y| detail(i, y)).sum::<f64>()
+            } else {
+                (0..small_width).map(|x| detail(x, i)).sum::<f64>()
+            }
+        })
+        .collect::<Vec<f64>>();
+
+    let mut sum = profile[..window as usize].iter().sum::<f64>();
+    let (mut best, mut best_sum) = (0, sum);
+    for start in 1..=(length - window) as usize {
+        sum += profile[start + window as usize - 1] - profile[start - 1];
+        if sum > best_sum {
+            best = start;
+            best_sum = sum;
+        }
+    }
+
+    let offset = (best as f64 / scale).round() as u32;
+    if horizontal {
+        (offset.min(width - crop_width), 0)
+    } else {
+        (0, offset.min(height - crop_height))
+    }
+}
+
+/// Whether `options` change pixels, so lossless formats have to be decoded and re-encoded too.
+pub fn changes_pixels(options: &CompressOptions) -> bool {
+    options.crop.is_some() || options.resize.is_some() || options.watermark.is_some()
+}
+
+/// Apply every pixel transform requested by `options`, in pipeline order: crop, then resize.
+pub fn apply(image: DynamicImage, options: &CompressOptions) -> DynamicImage {
+    let image = match &options.crop {
+        Some(crop) => crop.apply(image),
+        None => image,
+    };
+
+    match &options.resize {
+        Some(resize) => resize.apply(image),
+        None => image,
+    }
+}
+
+/// Apply the transforms of `options` to one frame of an animation.
+pub fn apply_frame(frame: Frame, options: &CompressOptions) -> Frame {
+    let delay = frame.delay();
+
+    // a smart crop per frame would make the animation jump, so frames are centred instead
+    let options = match options.crop {
+        Some(Crop::Smart { aspect }) => CompressOptions {
+            crop: Some(Crop::Gravity {
+                aspect,
+                gravity: Gravity::Center,
+            }),
+            ..options.clone()
+        },
+        _ => options.clone(),
+    };
+    let image = apply(DynamicImage::ImageRgba8(frame.into_buffer()), &options);
+
+    Frame::from_parts(image.to_rgba8(), 0, 0, delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_resize_target_size() {
+        let resize = Resize {
+            max_width: Some(1920),
+            max_height: None,
+        };
+        assert_eq!(resize.target_size(3840, 2160), (1920, 1080));
+        // never enlarge
+        assert_eq!(resize.target_size(640, 480), (640, 480));
+
+        let resize = Resize {
+            max_width: Some(320),
+            max_height: Some(320),
+        };
+        assert_eq!(resize.target_size(1000, 500), (320, 160));
+        assert_eq!(resize.target_size(500, 1000), (160, 320));
+    }
+
+    #[test]
+    fn test_crop_regions() {
+        // flat grey on the left, a detailed checkerboard on the right
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(400, 100, |x, y| {
+            if x < 300 || (x / 4 + y / 4) % 2 == 0 {
+                image::Rgb([128, 128, 128])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        }));
+        let square = AspectRatio {
+            width: 1,
+            height: 1,
+        };
+
+        let gravity = |gravity| Crop::Gravity {
+            aspect: square,
+            gravity,
+        };
+        assert_eq!(gravity(Gravity::Center).region(&image), (150, 0, 100, 100));
+        assert_eq!(gravity(Gravity::West).region(&image), (0, 0, 100, 100));
+        assert_eq!(
+            gravity(Gravity::SouthEast).region(&image),
+            (300, 0, 100, 100)
+        );
+        // the analysis runs on a downscaled copy, so the detailed part is found within a few pixels
+        let (x, _, width, _) = Crop::Smart { aspect: square }.region(&image);
+        assert!(x >= 290 && width == 100, "smart crop at {}", x);
+        assert_eq!(
+            Crop::Rect {
+                x: 350,
+                y: 20,
+                width: 100,
+                height: 100
+            }
+            .region(&image),
+            (350, 20, 50, 80)
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::{mpsc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::batch::BatchItem;
+use crate::cache::hash_file;
+use crate::compresser::CompressOptions;
+use crate::format_meta::ImageFormat;
+use crate::scheduler::{Job, ScheduleEvent, Scheduler};
+
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error("failed to start file watcher: {0}")]
+    Init(#[source] notify::Error),
+    #[error("failed to watch {path}: {source}")]
+    Watch {
+        path: String,
+        #[source]
+        source: notify::Error,
+    },
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+fn default_debounce_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WatchOptions {
+    pub options: CompressOptions,
+    #[serde(default = "default_recursive")]
+    pub recursive: bool,
+    /// how long a file must stay untouched, with an unchanged size, before it is compressed
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+}
+
+impl WatchOptions {
+    pub fn new(options: CompressOptions) -> Self {
+        Self {
+            options,
+            recursive: default_recursive(),
+            debounce_ms: default_debounce_ms(),
+        }
+    }
+}
+
+struct PendingFile {
+    last_event: Instant,
+    size: Option<u64>,
+}
+
+/// Compresses images as they are created or changed in the watched directories.
+///
+/// Files written by the watcher itself are remembered by content hash and ignored,
+/// so in-place compression and converted outputs don't trigger another round.
+pub struct Watcher {
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub fn start<I, P, F>(
+        dirs: I,
+        settings: WatchOptions,
+        on_item: F,
+    ) -> result::Result<Self, WatchError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+        F: Fn(BatchItem) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // the loop is gone once the watcher is stopped
+            let _ = sender.send(event);
+        })
+        .map_err(WatchError::Init)?;
+
+        let mode = if settings.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        for dir in dirs {
+            let dir = dir.as_ref();
+            watcher
+                .watch(dir, mode)
+                .map_err(|source| WatchError::Watch {
+                    path: dir.to_string_lossy().to_string(),
+                    source,
+                })?;
+        }
+
+        let thread = thread::spawn(move || watch_loop(receiver, settings, on_item));
+
+        Ok(Self {
+            watcher: Some(watcher),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop watching and wait for files already being compressed.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // dropping the watcher closes the channel, which ends the loop
+        self.watcher.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn watch_loop<F>(
+    receiver: mpsc::Receiver<notify::Result<notify::Event>>,
+    settings: WatchOptions,
+    on_item: F,
+) where
+    F: Fn(BatchItem) + Sync,
+{
+    let debounce = Duration::from_millis(settings.debounce_ms);
+    let tick = debounce.min(Duration::from_millis(250));
+    let scheduler = Scheduler::default();
+
+    let mut pending: HashMap<PathBuf, PendingFile> = HashMap::new();
+    let written: Mutex<HashMap<PathBuf, String>> = Mutex::new(HashMap::new());
+
+    loop {
+        match receiver.recv_timeout(tick) {
+            Ok(Ok(event)) => match event.kind {
+                EventKind::Create(_) | EventKind::Modify(_) => {
+                    for path in event.paths {
+                        pending.insert(
+                            path,
+                            PendingFile {
+                                last_event: Instant::now(),
+                                size: None,
+                            },
+                        );
+                    }
+                }
+                EventKind::Remove(_) => {
+                    for path in event.paths {
+                        pending.remove(&path);
+                    }
+                }
+                _ => {}
+            },
+            // a single failed notification doesn't stop watching
+            Ok(Err(_)) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let ready = take_settled(&mut pending, debounce);
+        let jobs = ready
+            .into_iter()
+            .filter(|path| is_candidate(path, &settings.options))
+            .filter(|path| !is_own_output(path, &written))
+            .map(|path| Job::new(path, settings.options.clone()))
+            .collect::<Vec<Job>>();
+        if jobs.is_empty() {
+            continue;
+        }
+
+        scheduler.run(jobs, |event| {
+            if let ScheduleEvent::Finished(item) = event {
+                if let Ok(result) = &item.result {
+                    // variants write several files per input, all of them are ours
+                    let paths = result
+                        .outputs
+                        .iter()
+                        .map(|output| &output.path)
+                        .chain(std::iter::once(&result.path));
+                    let mut written = written.lock().unwrap();
+                    for path in paths {
+                        if let Ok(hash) = hash_file(path) {
+                            written.insert(PathBuf::from(path), hash);
+                        }
+                    }
+                }
+                on_item(item);
+            }
+        });
+    }
+}
+
+// files that were quiet for the debounce period and kept the same size across two checks
+fn take_settled(pending: &mut HashMap<PathBuf, PendingFile>, debounce: Duration) -> Vec<PathBuf> {
+    let mut settled = Vec::new();
+    pending.retain(|path, file| {
+        if file.last_event.elapsed() < debounce {
+            return true;
+        }
+        let size = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            // vanished, e.g. a temporary file renamed by the exporting application
+            Err(_) => return false,
+        };
+        if file.size == Some(size) {
+            settled.push(path.clone());
+            return false;
+        }
+        file.size = Some(size);
+        file.last_event = Instant::now();
+        true
+    });
+    settled
+}
+
+fn is_candidate(path: &Path, options: &CompressOptions) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(true);
+
+    path.is_file() && !hidden && ImageFormat::detect(path, options.detection).is_ok()
+}
+
+fn is_own_output(path: &Path, written: &Mutex<HashMap<PathBuf, String>>) -> bool {
+    let written = written.lock().unwrap();
+    match written.get(path) {
+        Some(hash) => hash_file(path)
+            .map(|current| &current == hash)
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compresser::Variants;
+
+    #[test]
+    fn test_compress_new_file() {
+        let root = std::env::temp_dir().join("ashuk-watch-new-file");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let mut settings = WatchOptions::new(CompressOptions {
+            extension: "webp".to_string(),
+            quality: None,
+            ..Default::default()
+        });
+        settings.debounce_ms = 100;
+        let watcher = Watcher::start([&root], settings, move |item| {
+            let _ = sender.lock().unwrap().send(item);
+        })
+        .unwrap();
+
+        let image = image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        image.save(root.join("new.png")).unwrap();
+
+        let item = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(item.path, root.join("new.png"));
+        assert!(item.result.is_ok());
+        // the written webp must not be picked up again
+        assert!(receiver.recv_timeout(Duration::from_millis(800)).is_err());
+
+        watcher.stop();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_variant_outputs() {
+        let root = std::env::temp_dir().join("ashuk-watch-variants");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let mut settings = WatchOptions::new(CompressOptions {
+            extension: "png".to_string(),
+            quality: None,
+            variants: Some(Variants {
+                widths: vec![16, 8],
+                formats: vec!["png".to_string(), "webp".to_string()],
+            }),
+            ..Default::default()
+        });
+        settings.debounce_ms = 100;
+        let watcher = Watcher::start([&root], settings, move |item| {
+            let _ = sender.lock().unwrap().send(item);
+        })
+        .unwrap();
+
+        let image = image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        image.save(root.join("hero.png")).unwrap();
+
+        let item = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(item.path, root.join("hero.png"));
+        assert_eq!(item.result.unwrap().outputs.len(), 4);
+        // none of the four variants may be compressed again
+        assert!(receiver.recv_timeout(Duration::from_millis(800)).is_err());
+
+        watcher.stop();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use font8x8::legacy::BASIC_LEGACY;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Frame, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+use std::result;
+
+use crate::compresser::{self, CompressError};
+use crate::format_meta::FormatDetection;
+use crate::limits::Limits;
+use crate::transform::Gravity;
+
+const GLYPH_SIZE: u32 = 8;
+
+/// What is drawn on top of the image.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum WatermarkSource {
+    /// an image file, usually a PNG with alpha
+    Image(PathBuf),
+    /// ASCII text drawn with a bitmap font
+    Text(String),
+}
+
+fn default_position() -> Gravity {
+    Gravity::SouthEast
+}
+
+fn default_opacity() -> f32 {
+    0.5
+}
+
+fn default_scale() -> f32 {
+    0.2
+}
+
+fn default_margin() -> u32 {
+    16
+}
+
+fn default_color() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+/// Logo or text composited onto every output before encoding.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Watermark {
+    pub source: WatermarkSource,
+    #[serde(default = "default_position")]
+    pub position: Gravity,
+    /// 0 is invisible, 1 keeps the watermark's own alpha
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    /// watermark width as a fraction of the image width
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// distance to the image edges in pixels
+    #[serde(default = "default_margin")]
+    pub margin: u32,
+    /// color of text watermarks
+    #[serde(default = "default_color")]
+    pub color: [u8; 3],
+}
+
+impl Watermark {
+    pub fn new(source: WatermarkSource) -> Self {
+        Self {
+            source,
+            position: default_position(),
+            opacity: default_opacity(),
+            scale: default_scale(),
+            margin: default_margin(),
+            color: default_color(),
+        }
+    }
+
+    /// Decode within `limits` or render the watermark once, so it can be applied to many images.
+    pub fn load(&self, limits: &Limits) -> result::Result<Overlay, CompressError> {
+        let (image, filter) = match &self.source {
+            WatermarkSource::Image(path) => {
+                let path = path.to_string_lossy();
+                let detected = compresser::detect(&path, FormatDetection::Content)?;
+                let image = compresser::decode(&path, detected.format, limits)?.to_rgba8();
+                (image, FilterType::Lanczos3)
+            }
+            // bitmap text stays sharp when scaled by nearest neighbour
+            WatermarkSource::Text(text) => (render_text(text, self.color), FilterType::Nearest),
+        };
+
+        Ok(Overlay {
+            image,
+            filter,
+            watermark: self.clone(),
+        })
+    }
+}
+
+/// A loaded watermark.
+pub struct Overlay {
+    image: RgbaImage,
+    filter: FilterType,
+    watermark: Watermark,
+}
+
+impl Overlay {
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let (width, height) = (image.width(), image.height());
+        let margin = self.watermark.margin;
+        let available_width = width.saturating_sub(margin.saturating_mul(2));
+        let available_height = height.saturating_sub(margin.saturating_mul(2));
+        if available_width == 0 || available_height == 0 || self.image.width() == 0 {
+            return image;
+        }
+
+        // scale to the requested share of the width, without overflowing the image
+        let target_width = (width as f32 * self.watermark.scale.max(0.0)).round() as u32;
+        let ratio = f32::min(
+            target_width.min(available_width) as f32 / self.image.width() as f32,
+            available_height as f32 / self.image.height() as f32,
+        );
+        let overlay_width = ((self.image.width() as f32 * ratio).round() as u32).max(1);
+        let overlay_height = ((self.image.height() as f32 * ratio).round() as u32).max(1);
+        let mut overlay = imageops::resize(&self.image, overlay_width, overlay_height, self.filter);
+
+        let opacity = self.watermark.opacity.clamp(0.0, 1.0);
+        for pixel in overlay.pixels_mut() {
+            pixel.0[3] = (pixel.0[3] as f32 * opacity).round() as u8;
+        }
+
+        let (free_x, free_y) = (
+            available_width - overlay_width,
+            available_height - overlay_height,
+        );
+        let x = margin
+            + match self.watermark.position {
+                Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+                Gravity::East | Gravity::NorthEast | Gravity::SouthEast => free_x,
+                _ => free_x / 2,
+            };
+        let y = margin
+            + match self.watermark.position {
+                Gravity::North | Gravity::NorthWest | Gravity::NorthEast => 0,
+                Gravity::South | Gravity::SouthWest | Gravity::SouthEast => free_y,
+                _ => free_y / 2,
+            };
+
+        let has_alpha = image.color().has_alpha();
+        let mut canvas = image.to_rgba8();
+        imageops::overlay(&mut canvas, &overlay, x as i64, y as i64);
+
+        // keep opaque images opaque, so encoders don't write an alpha channel
+        if has_alpha {
+            DynamicImage::ImageRgba8(canvas)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+        }
+    }
+
+    pub fn apply_frame(&self, frame: Frame) -> Frame {
+        let delay = frame.delay();
+        let image = self.apply(DynamicImage::ImageRgba8(frame.into_buffer()));
+
+        Frame::from_parts(image.to_rgba8(), 0, 0, delay)
+    }
+}
+
+/// Render ASCII `text` with the 8×8 bitmap font, one pixel per font dot.
+pub(crate) fn render_text(text: &str, color: [u8; 3]) -> RgbaImage {
+    let chars = text
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .collect::<Vec<char>>();
+    let [r, g, b] = color;
+    let mut image = RgbaImage::new((chars.len() as u32 * GLYPH_SIZE).max(1), GLYPH_SIZE);
+
+    for (i, c) in chars.iter().enumerate() {
+        let glyph = BASIC_LEGACY[*c as usize & 0x7F];
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_SIZE {
+                // the lowest bit is the leftmost pixel
+                if bits >> column & 1 == 1 {
+                    let x = i as u32 * GLYPH_SIZE + column;
+                    image.put_pixel(x, row as u32, Rgba([r, g, b, 255]));
+                }
+            }
+        }
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_text_watermark_position() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(200, 100));
+        let watermark = Watermark {
+            opacity: 1.0,
+            scale: 0.5,
+            margin: 10,
+            ..Watermark::new(WatermarkSource::Text("ashuk".to_string()))
+        };
+
+        let result = watermark.load(&Limits::default()).unwrap().apply(image);
+        assert!(!result.color().has_alpha());
+        let result = result.to_rgb8();
+
+        // 40px of text scaled to 100px wide and 20px high, in the bottom right corner
+        let lit = result
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel.0 != [0, 0, 0])
+            .map(|(x, y, _)| (x, y))
+            .collect::<Vec<(u32, u32)>>();
+        assert!(!lit.is_empty());
+        assert!(lit
+            .iter()
+            .all(|(x, y)| *x >= 90 && *x < 190 && *y >= 70 && *y < 90));
+
+        // a margin wider than the image leaves no room instead of overflowing
+        let watermark = Watermark {
+            margin: u32::MAX,
+            ..watermark
+        };
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(200, 100));
+        let result = watermark.load(&Limits::default()).unwrap().apply(image);
+        assert!(result.to_rgb8().pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    }
+}